@@ -0,0 +1,381 @@
+use embedded_hal::i2c::{ErrorType, I2c, Operation};
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// MCP23017 registers, IOCON.BANK = 0 so the A and B registers follow each other
+const MCP23017_IODIRA: u8 = 0x00;
+const MCP23017_OLATA: u8 = 0x14;
+
+// Writes of the expander outputs before a flush fails
+const WRITE_ATTEMPTS: u32 = 5;
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// Output driving a relay
+pub trait RelayPin: Send {
+    fn set_high(&mut self);
+    fn set_low(&mut self);
+    // Write pending changes, pins which switch at once have nothing to do
+    // A failed write stays pending, the next flush tries it again.
+    fn flush(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// I2C bus shared by several drivers, each transaction holds the bus
+// The DS3231 and the GPIO expanders are on the same bus and used from different threads.
+pub struct SharedI2c<T>(Arc<Mutex<T>>);
+
+impl<T> SharedI2c<T> {
+    pub fn new(i2c: T) -> Self {
+        Self(Arc::new(Mutex::new(i2c)))
+    }
+}
+
+impl<T> Clone for SharedI2c<T> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<T: ErrorType> ErrorType for SharedI2c<T> {
+    type Error = T::Error;
+}
+
+impl<T: I2c> I2c for SharedI2c<T> {
+    fn read(&mut self, address: u8, read: &mut [u8]) -> Result<(), Self::Error> {
+        self.0.lock().unwrap().read(address, read)
+    }
+
+    fn write(&mut self, address: u8, write: &[u8]) -> Result<(), Self::Error> {
+        self.0.lock().unwrap().write(address, write)
+    }
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.0.lock().unwrap().write_read(address, write, read)
+    }
+
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        self.0.lock().unwrap().transaction(address, operations)
+    }
+}
+
+// Output port of a GPIO expander, bit n of levels is pin n
+pub trait OutputPort: Send {
+    type Error: Debug;
+
+    // Make the given pins outputs, starting at levels
+    fn init(&mut self, outputs: u16, levels: u16) -> Result<(), Self::Error>;
+    // Set every output in one transfer
+    fn write(&mut self, levels: u16) -> Result<(), Self::Error>;
+}
+
+// PCF8574 and PCF8574A, 8 quasi-bidirectional pins
+// A pin which is not an output is written high, so it can still be read.
+pub struct Pcf8574<I2C> {
+    i2c: I2C,
+    address: u8,
+    inputs: u8,
+}
+
+impl<I2C: I2c> Pcf8574<I2C> {
+    pub fn new(i2c: I2C, address: u8) -> Self {
+        Self {
+            i2c,
+            address,
+            inputs: 0xff,
+        }
+    }
+}
+
+impl<I2C: I2c + Send> OutputPort for Pcf8574<I2C> {
+    type Error = I2C::Error;
+
+    fn init(&mut self, outputs: u16, levels: u16) -> Result<(), Self::Error> {
+        self.inputs = !(outputs as u8);
+        self.write(levels)
+    }
+
+    fn write(&mut self, levels: u16) -> Result<(), Self::Error> {
+        self.i2c.write(self.address, &[levels as u8 | self.inputs])
+    }
+}
+
+// MCP23017, 16 pins, GPA0-7 are pins 0-7 and GPB0-7 are pins 8-15
+pub struct Mcp23017<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C: I2c> Mcp23017<I2C> {
+    pub fn new(i2c: I2C, address: u8) -> Self {
+        Self { i2c, address }
+    }
+}
+
+impl<I2C: I2c + Send> OutputPort for Mcp23017<I2C> {
+    type Error = I2C::Error;
+
+    fn init(&mut self, outputs: u16, levels: u16) -> Result<(), Self::Error> {
+        // Latch the levels before the pins become outputs, relays would click otherwise
+        self.write(levels)?;
+        let [a, b] = (!outputs).to_le_bytes();
+        self.i2c.write(self.address, &[MCP23017_IODIRA, a, b])
+    }
+
+    fn write(&mut self, levels: u16) -> Result<(), Self::Error> {
+        let [a, b] = levels.to_le_bytes();
+        self.i2c.write(self.address, &[MCP23017_OLATA, a, b])
+    }
+}
+
+// Output levels of an expander, written to the chip on flush
+struct Latch<P> {
+    port: P,
+    levels: u16,
+    dirty: bool,
+}
+
+impl<P: OutputPort> Latch<P> {
+    fn flush(&mut self) -> Result<(), P::Error> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let mut attempt = 1;
+        loop {
+            match self.port.write(self.levels) {
+                Ok(()) => {
+                    self.dirty = false;
+                    return Ok(());
+                }
+                Err(e) if attempt >= WRITE_ATTEMPTS => return Err(e),
+                Err(_) => {
+                    attempt += 1;
+                    thread::sleep(WRITE_RETRY_DELAY);
+                }
+            }
+        }
+    }
+}
+
+// GPIO expander whose pins drive relays
+// Pin changes are batched, the relays of one expander switch together on flush.
+pub struct Expander<P> {
+    latch: Arc<Mutex<Latch<P>>>,
+}
+
+impl<P: OutputPort> Expander<P> {
+    pub fn new(mut port: P, outputs: u16, levels: u16) -> Result<Self, P::Error> {
+        port.init(outputs, levels)?;
+        Ok(Self {
+            latch: Arc::new(Mutex::new(Latch {
+                port,
+                levels,
+                dirty: false,
+            })),
+        })
+    }
+
+    pub fn pin(&self, pin: u8) -> ExpanderPin<P> {
+        ExpanderPin {
+            latch: Arc::clone(&self.latch),
+            mask: 1 << pin,
+        }
+    }
+}
+
+pub struct ExpanderPin<P> {
+    latch: Arc<Mutex<Latch<P>>>,
+    mask: u16,
+}
+
+impl<P: OutputPort> ExpanderPin<P> {
+    fn set(&mut self, high: bool) {
+        let mut latch = self.latch.lock().unwrap();
+        let levels = if high {
+            latch.levels | self.mask
+        } else {
+            latch.levels & !self.mask
+        };
+        if levels != latch.levels {
+            latch.levels = levels;
+            latch.dirty = true;
+        }
+    }
+}
+
+impl<P: OutputPort> RelayPin for ExpanderPin<P> {
+    fn set_high(&mut self) {
+        self.set(true);
+    }
+
+    fn set_low(&mut self) {
+        self.set(false);
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.latch
+            .lock()
+            .unwrap()
+            .flush()
+            .map_err(|e| format!("Failed to write expander outputs: {:?}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_hal::i2c::ErrorKind;
+
+    #[derive(Debug)]
+    struct MockError;
+
+    impl embedded_hal::i2c::Error for MockError {
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::Other
+        }
+    }
+
+    // Address and bytes of one write
+    type Write = (u8, Vec<u8>);
+
+    // Records the written bytes, the next `failures` transactions fail
+    #[derive(Clone, Default)]
+    struct MockI2c {
+        writes: Arc<Mutex<Vec<Write>>>,
+        failures: Arc<Mutex<u32>>,
+    }
+
+    impl MockI2c {
+        fn take_writes(&self) -> Vec<Write> {
+            std::mem::take(&mut *self.writes.lock().unwrap())
+        }
+    }
+
+    impl ErrorType for MockI2c {
+        type Error = MockError;
+    }
+
+    impl I2c for MockI2c {
+        fn transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> Result<(), Self::Error> {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(MockError);
+            }
+            for operation in operations {
+                if let Operation::Write(data) = operation {
+                    self.writes.lock().unwrap().push((address, data.to_vec()));
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn mcp23017_latches_levels_before_directions() {
+        let i2c = MockI2c::default();
+        // Pins 0, 1 and 8 are outputs, pin 1 drives an active-low relay
+        Expander::new(Mcp23017::new(i2c.clone(), 0x20), 0x0103, 0x0002).unwrap();
+        assert_eq!(
+            i2c.take_writes(),
+            vec![
+                (0x20, vec![MCP23017_OLATA, 0x02, 0x00]),
+                (0x20, vec![MCP23017_IODIRA, 0xfc, 0xfe]),
+            ]
+        );
+    }
+
+    #[test]
+    fn pcf8574_input_bits_stay_high() {
+        let i2c = MockI2c::default();
+        let expander = Expander::new(Pcf8574::new(i2c.clone(), 0x38), 0x03, 0x00).unwrap();
+        let mut pin0 = expander.pin(0);
+        let mut pin1 = expander.pin(1);
+        pin0.set_high();
+        pin0.flush().unwrap();
+        pin0.set_low();
+        pin1.set_high();
+        pin1.flush().unwrap();
+        assert_eq!(
+            i2c.take_writes(),
+            vec![(0x38, vec![0xfc]), (0x38, vec![0xfd]), (0x38, vec![0xfe])]
+        );
+    }
+
+    #[test]
+    fn pin_changes_are_written_once_per_flush() {
+        let i2c = MockI2c::default();
+        let expander = Expander::new(Mcp23017::new(i2c.clone(), 0x21), 0x000f, 0x0000).unwrap();
+        i2c.take_writes();
+        let mut pins: Vec<_> = (0..4).map(|pin| expander.pin(pin)).collect();
+        for pin in &mut pins {
+            pin.set_high();
+        }
+        for pin in &mut pins {
+            pin.flush().unwrap();
+        }
+        assert_eq!(
+            i2c.take_writes(),
+            vec![(0x21, vec![MCP23017_OLATA, 0x0f, 0x00])]
+        );
+    }
+
+    #[test]
+    fn clean_latch_is_not_written() {
+        let i2c = MockI2c::default();
+        let expander = Expander::new(Pcf8574::new(i2c.clone(), 0x20), 0x01, 0x01).unwrap();
+        i2c.take_writes();
+        let mut pin = expander.pin(0);
+        pin.flush().unwrap();
+        // Already at that level
+        pin.set_high();
+        pin.flush().unwrap();
+        assert!(i2c.take_writes().is_empty());
+    }
+
+    #[test]
+    fn failed_write_stays_pending() {
+        let i2c = MockI2c::default();
+        let bus = SharedI2c::new(i2c.clone());
+        let expander = Expander::new(Mcp23017::new(bus, 0x20), 0x0001, 0x0000).unwrap();
+        i2c.take_writes();
+        let mut pin = expander.pin(0);
+        pin.set_high();
+        *i2c.failures.lock().unwrap() = WRITE_ATTEMPTS;
+        assert!(pin.flush().is_err());
+        assert!(i2c.take_writes().is_empty());
+        // Written by the next flush
+        pin.flush().unwrap();
+        assert_eq!(
+            i2c.take_writes(),
+            vec![(0x20, vec![MCP23017_OLATA, 0x01, 0x00])]
+        );
+    }
+
+    #[test]
+    fn write_is_retried() {
+        let i2c = MockI2c::default();
+        let expander = Expander::new(Pcf8574::new(i2c.clone(), 0x20), 0x01, 0x00).unwrap();
+        i2c.take_writes();
+        let mut pin = expander.pin(0);
+        pin.set_high();
+        *i2c.failures.lock().unwrap() = WRITE_ATTEMPTS - 1;
+        pin.flush().unwrap();
+        assert_eq!(i2c.take_writes(), vec![(0x20, vec![0xff])]);
+    }
+}
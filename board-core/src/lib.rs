@@ -0,0 +1,7 @@
+// Platform independent parts of the board firmware
+// Nothing here depends on ESP-IDF, so it is tested on the host with cargo test.
+
+pub mod expander;
+pub mod ota;
+pub mod plan;
+pub mod schedule_hash;
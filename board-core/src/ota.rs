@@ -0,0 +1,262 @@
+use log::info;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+// Time to wait for a requested chunk before asking again
+pub const CHUNK_TIMEOUT: Duration = Duration::from_secs(30);
+// Requests of the same chunk before the download is given up
+pub const CHUNK_ATTEMPTS: u32 = 5;
+
+// Part of a firmware image sent by the server
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub version: String,
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+// Connection to the server the image is downloaded from
+pub trait ChunkTransport {
+    // Ask the server for the chunk of the image at offset
+    fn request(&mut self, version: &str, offset: u64);
+    // Next chunk which arrives before the deadline, None once it has passed
+    // Chunks of earlier requests are returned too, the download drops them.
+    fn receive(&mut self, deadline: Instant) -> Result<Option<Chunk>, DownloadError>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DownloadError {
+    // The transport failed, e.g. its channel was closed
+    Transport(String),
+    // The image could not be written to the update partition
+    Write(String),
+    // The chunk is empty or goes beyond the image size
+    InvalidChunk { offset: u64 },
+    NoAnswer { offset: u64 },
+    HashMismatch { expected: String, actual: String },
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownloadError::Transport(e) => write!(f, "Transport error: {}", e),
+            DownloadError::Write(e) => write!(f, "Write error: {}", e),
+            DownloadError::InvalidChunk { offset } => {
+                write!(f, "Invalid chunk at offset {}", offset)
+            }
+            DownloadError::NoAnswer { offset } => {
+                write!(f, "No answer to chunk request at offset {}", offset)
+            }
+            DownloadError::HashMismatch { expected, actual } => {
+                write!(f, "SHA-256 mismatch, expected {} got {}", expected, actual)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DownloadError {}
+
+// Request the image chunk by chunk and pass each one to write in order
+// The image is accepted once size bytes arrived and their SHA-256 matches.
+pub fn download<T: ChunkTransport>(
+    transport: &mut T,
+    version: &str,
+    size: u64,
+    sha256: &str,
+    mut write: impl FnMut(&[u8]) -> Result<(), DownloadError>,
+) -> Result<(), DownloadError> {
+    let mut hasher = Sha256::new();
+    let mut offset = 0;
+    while offset < size {
+        let data = receive_chunk(transport, version, offset)?;
+        if data.is_empty() || offset + data.len() as u64 > size {
+            return Err(DownloadError::InvalidChunk { offset });
+        }
+        write(&data)?;
+        hasher.update(&data);
+        offset += data.len() as u64;
+    }
+
+    let actual: String = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    if actual != sha256 {
+        return Err(DownloadError::HashMismatch {
+            expected: sha256.to_string(),
+            actual,
+        });
+    }
+    Ok(())
+}
+
+// Ask the server for the chunk at offset, again if it does not arrive in time
+fn receive_chunk<T: ChunkTransport>(
+    transport: &mut T,
+    version: &str,
+    offset: u64,
+) -> Result<Vec<u8>, DownloadError> {
+    for _ in 0..CHUNK_ATTEMPTS {
+        transport.request(version, offset);
+        let deadline = Instant::now() + CHUNK_TIMEOUT;
+        while let Some(chunk) = transport.receive(deadline)? {
+            if chunk.version == version && chunk.offset == offset {
+                return Ok(chunk.data);
+            }
+            // Answer to an earlier request
+        }
+        info!("Chunk {} of firmware {} timed out", offset, version);
+    }
+    Err(DownloadError::NoAnswer { offset })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    // Replies in order, None is a timeout; timeouts once the replies run out
+    struct MockTransport {
+        replies: VecDeque<Option<Chunk>>,
+        requests: Vec<u64>,
+    }
+
+    impl MockTransport {
+        fn new(replies: Vec<Option<Chunk>>) -> Self {
+            Self {
+                replies: replies.into(),
+                requests: Vec::new(),
+            }
+        }
+    }
+
+    impl ChunkTransport for MockTransport {
+        fn request(&mut self, version: &str, offset: u64) {
+            assert_eq!(version, "1.2.0");
+            self.requests.push(offset);
+        }
+
+        fn receive(&mut self, _deadline: Instant) -> Result<Option<Chunk>, DownloadError> {
+            Ok(self.replies.pop_front().flatten())
+        }
+    }
+
+    const IMAGE: &[u8] = b"0123456789";
+
+    fn chunk(offset: u64, len: usize) -> Option<Chunk> {
+        let start = offset as usize;
+        Some(Chunk {
+            version: "1.2.0".to_string(),
+            offset,
+            data: IMAGE[start..(start + len).min(IMAGE.len())].to_vec(),
+        })
+    }
+
+    fn sha256_hex(data: &[u8]) -> String {
+        Sha256::digest(data)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    // Download IMAGE, the written bytes are returned
+    fn run(transport: &mut MockTransport, sha256: &str) -> Result<Vec<u8>, DownloadError> {
+        let mut written = Vec::new();
+        download(transport, "1.2.0", IMAGE.len() as u64, sha256, |data| {
+            written.extend_from_slice(data);
+            Ok(())
+        })?;
+        Ok(written)
+    }
+
+    #[test]
+    fn in_order_chunks() {
+        let mut transport = MockTransport::new(vec![chunk(0, 4), chunk(4, 4), chunk(8, 4)]);
+        assert_eq!(run(&mut transport, &sha256_hex(IMAGE)), Ok(IMAGE.to_vec()));
+        assert_eq!(transport.requests, vec![0, 4, 8]);
+    }
+
+    #[test]
+    fn stale_and_duplicate_replies_are_dropped() {
+        let mut other_version = chunk(4, 4);
+        other_version.as_mut().unwrap().version = "1.1.0".to_string();
+        let mut transport = MockTransport::new(vec![
+            chunk(0, 4),
+            // Duplicate answer to the first request
+            chunk(0, 4),
+            other_version,
+            chunk(4, 4),
+            chunk(8, 4),
+        ]);
+        assert_eq!(run(&mut transport, &sha256_hex(IMAGE)), Ok(IMAGE.to_vec()));
+        assert_eq!(transport.requests, vec![0, 4, 8]);
+    }
+
+    #[test]
+    fn timed_out_chunk_is_requested_again() {
+        let mut transport =
+            MockTransport::new(vec![chunk(0, 4), None, None, chunk(4, 4), chunk(8, 4)]);
+        assert_eq!(run(&mut transport, &sha256_hex(IMAGE)), Ok(IMAGE.to_vec()));
+        assert_eq!(transport.requests, vec![0, 4, 4, 4, 8]);
+    }
+
+    #[test]
+    fn gives_up_after_the_attempts() {
+        let mut transport = MockTransport::new(vec![chunk(0, 4)]);
+        assert_eq!(
+            run(&mut transport, &sha256_hex(IMAGE)),
+            Err(DownloadError::NoAnswer { offset: 4 })
+        );
+        assert_eq!(transport.requests.len(), 1 + CHUNK_ATTEMPTS as usize);
+    }
+
+    #[test]
+    fn short_chunks_are_followed() {
+        // A shorter chunk than expected moves the next request
+        let mut transport = MockTransport::new(vec![chunk(0, 3), chunk(3, 7)]);
+        assert_eq!(run(&mut transport, &sha256_hex(IMAGE)), Ok(IMAGE.to_vec()));
+        assert_eq!(transport.requests, vec![0, 3]);
+    }
+
+    #[test]
+    fn empty_or_oversize_chunk_is_rejected() {
+        let mut transport = MockTransport::new(vec![chunk(0, 0)]);
+        assert_eq!(
+            run(&mut transport, &sha256_hex(IMAGE)),
+            Err(DownloadError::InvalidChunk { offset: 0 })
+        );
+
+        let mut oversize = chunk(8, 2);
+        oversize.as_mut().unwrap().data.push(b'!');
+        let mut transport = MockTransport::new(vec![chunk(0, 8), oversize]);
+        assert_eq!(
+            run(&mut transport, &sha256_hex(IMAGE)),
+            Err(DownloadError::InvalidChunk { offset: 8 })
+        );
+    }
+
+    #[test]
+    fn hash_mismatch_is_rejected() {
+        let mut transport = MockTransport::new(vec![chunk(0, 10)]);
+        let expected = sha256_hex(b"something else");
+        assert_eq!(
+            run(&mut transport, &expected),
+            Err(DownloadError::HashMismatch {
+                expected,
+                actual: sha256_hex(IMAGE),
+            })
+        );
+    }
+
+    #[test]
+    fn write_error_stops_the_download() {
+        let mut transport = MockTransport::new(vec![chunk(0, 4), chunk(4, 4)]);
+        let res = download(&mut transport, "1.2.0", 10, &sha256_hex(IMAGE), |_| {
+            Err(DownloadError::Write("partition full".to_string()))
+        });
+        assert_eq!(res, Err(DownloadError::Write("partition full".to_string())));
+        assert_eq!(transport.requests, vec![0]);
+    }
+}
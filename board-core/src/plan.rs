@@ -0,0 +1,241 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ZoneAction {
+    pub zone_ids: Vec<String>,
+    pub duration_seconds: i32,
+    // Split the duration into cycles with a soak pause between them
+    #[serde(default)]
+    pub cycles: Option<u32>,
+    #[serde(default)]
+    pub soak_seconds: Option<u32>,
+}
+
+// One step of a running program
+// Zone actions are split into their cycles,
+// soak and delay steps keep every zone relay closed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunStep {
+    Water {
+        zone_action: ZoneAction,
+        // Index of the zone action in the program
+        zone_index: usize,
+        cycle: u32,
+        cycles: u32,
+    },
+    Soak {
+        seconds: u32,
+    },
+    // Gap between sequential zones, the master valve stays on
+    Delay {
+        seconds: u32,
+    },
+}
+
+impl RunStep {
+    pub fn duration_seconds(&self) -> u64 {
+        match self {
+            RunStep::Water { zone_action, .. } => zone_action.duration_seconds.max(0) as u64,
+            RunStep::Soak { seconds } | RunStep::Delay { seconds } => *seconds as u64,
+        }
+    }
+}
+
+// Build the run plan of the zone actions of a program
+// A zone action with cycles is split into equal cycles with a soak pause
+// after each one. During the soak pause the cycles of other zone actions
+// are run, in program order; the board only waits when no zone action is ready.
+// A cycle is at least one second, a short zone action gets fewer cycles.
+// Sequential zones are separated by the inter-zone delay.
+pub fn build_run_plan(zones: &[ZoneAction], inter_zone_delay: u32) -> Vec<RunStep> {
+    struct Pending {
+        cycles: u32,
+        done: u32,
+        // Second of the plan when the next cycle may start
+        ready_at: u64,
+    }
+
+    let mut pending: Vec<Pending> = zones
+        .iter()
+        .map(|zone| Pending {
+            cycles: zone
+                .cycles
+                .unwrap_or(1)
+                .min(zone.duration_seconds.max(0) as u32)
+                .max(1),
+            done: 0,
+            ready_at: 0,
+        })
+        .collect();
+
+    let mut plan = Vec::new();
+    let mut now: u64 = 0;
+
+    loop {
+        let ready = pending
+            .iter()
+            .position(|p| p.done < p.cycles && p.ready_at <= now);
+
+        match ready {
+            Some(i) => {
+                let zone = &zones[i];
+                let p = &mut pending[i];
+                let total = zone.duration_seconds.max(0);
+                let cycle_seconds = total / p.cycles as i32;
+                // The last cycle gets the remainder
+                let seconds = if p.done + 1 == p.cycles {
+                    total - cycle_seconds * (p.cycles as i32 - 1)
+                } else {
+                    cycle_seconds
+                };
+
+                // Another zone action has just closed, cycles of the same one follow each other
+                if inter_zone_delay > 0
+                    && matches!(plan.last(), Some(RunStep::Water { zone_index, .. }) if *zone_index != i)
+                {
+                    plan.push(RunStep::Delay {
+                        seconds: inter_zone_delay,
+                    });
+                    now += inter_zone_delay as u64;
+                }
+
+                p.done += 1;
+                now += seconds as u64;
+                p.ready_at = now + zone.soak_seconds.unwrap_or(0) as u64;
+
+                plan.push(RunStep::Water {
+                    zone_action: ZoneAction {
+                        duration_seconds: seconds,
+                        ..zone.clone()
+                    },
+                    zone_index: i,
+                    cycle: p.done,
+                    cycles: p.cycles,
+                });
+            }
+            None => {
+                // Wait for the first zone action which finishes soaking
+                let Some(ready_at) = pending
+                    .iter()
+                    .filter(|p| p.done < p.cycles)
+                    .map(|p| p.ready_at)
+                    .min()
+                else {
+                    break;
+                };
+                plan.push(RunStep::Soak {
+                    seconds: (ready_at - now) as u32,
+                });
+                now = ready_at;
+            }
+        }
+    }
+
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone(id: &str, seconds: i32, cycles: Option<u32>, soak: Option<u32>) -> ZoneAction {
+        ZoneAction {
+            zone_ids: vec![id.to_string()],
+            duration_seconds: seconds,
+            cycles,
+            soak_seconds: soak,
+        }
+    }
+
+    // Zone index, cycle, cycles and seconds of a water step, None for soak and delay steps
+    fn water(step: &RunStep) -> Option<(usize, u32, u32, i32)> {
+        match step {
+            RunStep::Water {
+                zone_action,
+                zone_index,
+                cycle,
+                cycles,
+            } => Some((*zone_index, *cycle, *cycles, zone_action.duration_seconds)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn sequential_zones_with_delay() {
+        let plan = build_run_plan(&[zone("a", 60, None, None), zone("b", 30, None, None)], 10);
+        assert_eq!(plan.len(), 3);
+        assert_eq!(water(&plan[0]), Some((0, 1, 1, 60)));
+        assert_eq!(plan[1], RunStep::Delay { seconds: 10 });
+        assert_eq!(water(&plan[2]), Some((1, 1, 1, 30)));
+    }
+
+    #[test]
+    fn cycles_interleave_during_soak() {
+        let plan = build_run_plan(
+            &[
+                zone("a", 600, Some(3), Some(300)),
+                zone("b", 200, None, None),
+            ],
+            0,
+        );
+        assert_eq!(water(&plan[0]), Some((0, 1, 3, 200)));
+        // b runs while a soaks, the board waits for the rest of the soak
+        assert_eq!(water(&plan[1]), Some((1, 1, 1, 200)));
+        assert_eq!(plan[2], RunStep::Soak { seconds: 100 });
+        assert_eq!(water(&plan[3]), Some((0, 2, 3, 200)));
+        assert_eq!(plan[4], RunStep::Soak { seconds: 300 });
+        assert_eq!(water(&plan[5]), Some((0, 3, 3, 200)));
+        assert_eq!(plan.len(), 6);
+    }
+
+    #[test]
+    fn last_cycle_gets_the_remainder() {
+        let plan = build_run_plan(&[zone("a", 100, Some(3), None)], 0);
+        let seconds: Vec<i32> = plan.iter().filter_map(water).map(|w| w.3).collect();
+        assert_eq!(seconds, vec![33, 33, 34]);
+        // Without soak the cycles follow each other
+        assert!(plan.iter().all(|s| water(s).is_some()));
+    }
+
+    #[test]
+    fn more_cycles_than_seconds() {
+        let plan = build_run_plan(&[zone("a", 3, Some(5), Some(10))], 0);
+        let cycles: Vec<(u32, u32, i32)> = plan
+            .iter()
+            .filter_map(water)
+            .map(|(_, cycle, cycles, seconds)| (cycle, cycles, seconds))
+            .collect();
+        assert_eq!(cycles, vec![(1, 3, 1), (2, 3, 1), (3, 3, 1)]);
+        assert!(plan.iter().all(|s| s.duration_seconds() > 0));
+    }
+
+    #[test]
+    fn no_delay_after_soak() {
+        let plan = build_run_plan(&[zone("a", 20, Some(2), Some(30))], 15);
+        assert_eq!(water(&plan[0]), Some((0, 1, 2, 10)));
+        assert_eq!(plan[1], RunStep::Soak { seconds: 30 });
+        assert_eq!(water(&plan[2]), Some((0, 2, 2, 10)));
+        assert_eq!(plan.len(), 3);
+    }
+
+    #[test]
+    fn no_delay_between_cycles_of_one_zone() {
+        let plan = build_run_plan(
+            &[zone("a", 20, Some(2), None), zone("b", 10, None, None)],
+            15,
+        );
+        assert_eq!(water(&plan[0]), Some((0, 1, 2, 10)));
+        assert_eq!(water(&plan[1]), Some((0, 2, 2, 10)));
+        assert_eq!(plan[2], RunStep::Delay { seconds: 15 });
+        assert_eq!(water(&plan[3]), Some((1, 1, 1, 10)));
+        assert_eq!(plan.len(), 4);
+    }
+
+    #[test]
+    fn empty_and_zero_length_zones() {
+        assert!(build_run_plan(&[], 10).is_empty());
+        let plan = build_run_plan(&[zone("a", 0, Some(4), None)], 0);
+        assert_eq!(plan.len(), 1);
+        assert_eq!(water(&plan[0]), Some((0, 1, 1, 0)));
+    }
+}
@@ -0,0 +1,126 @@
+use std::fmt::Write;
+
+use crate::plan::ZoneAction;
+
+// Program fields which make up the schedule hash
+pub struct ProgramLine<'a> {
+    pub id: &'a str,
+    pub name: &'a str,
+    pub weekdays: &'a [i8],
+    // Formatted as %H:%M:%S
+    pub start_time: &'a str,
+    pub active: bool,
+    pub water_budget: Option<u16>,
+    pub inter_zone_delay_seconds: Option<u32>,
+    pub catch_up_minutes: Option<u32>,
+}
+
+// Hash of the schedule content without its version, shared by the server and the firmware
+// Fields are written in a fixed order, so the hash does not depend on the serializer.
+pub struct ContentHasher {
+    canonical: String,
+}
+
+impl ContentHasher {
+    // Start with the global water budget
+    pub fn new(percent: u16, monthly: &[u16]) -> Self {
+        let monthly: Vec<String> = monthly.iter().map(|p| p.to_string()).collect();
+        let mut canonical = String::new();
+        let _ = writeln!(canonical, "budget\x1f{}\x1f{}", percent, monthly.join(","));
+        Self { canonical }
+    }
+
+    // Add a program, followed by its zone actions
+    pub fn program(&mut self, program: &ProgramLine) {
+        let weekdays: Vec<String> = program.weekdays.iter().map(|d| d.to_string()).collect();
+        let _ = writeln!(
+            self.canonical,
+            "program\x1f{}\x1f{}\x1f{}\x1f{}\x1f{}\x1f{}\x1f{}\x1f{}",
+            program.id,
+            program.name,
+            weekdays.join(","),
+            program.start_time,
+            program.active,
+            optional(program.water_budget.map(u32::from)),
+            optional(program.inter_zone_delay_seconds),
+            optional(program.catch_up_minutes),
+        );
+    }
+
+    pub fn zone(&mut self, zone: &ZoneAction) {
+        let _ = writeln!(
+            self.canonical,
+            "zone\x1f{}\x1f{}\x1f{}\x1f{}",
+            zone.zone_ids.join(","),
+            zone.duration_seconds,
+            optional(zone.cycles),
+            optional(zone.soak_seconds),
+        );
+    }
+
+    pub fn finish(self) -> String {
+        format!("{:016x}", fnv1a(self.canonical.as_bytes()))
+    }
+}
+
+// 64-bit FNV-1a hash
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn optional(value: Option<u32>) -> String {
+    value.map_or("-".to_string(), |v| v.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fnv1a_reference_values() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+    }
+
+    // Boards with this hash are not sent the schedule again, it must not change
+    #[test]
+    fn schedule_test_vector() {
+        let mut hasher =
+            ContentHasher::new(80, &[50, 60, 70, 80, 90, 100, 120, 120, 100, 80, 60, 50]);
+        hasher.program(&ProgramLine {
+            id: "p1",
+            name: "Front lawn",
+            weekdays: &[1, 3, 5],
+            start_time: "06:30:00",
+            active: true,
+            water_budget: None,
+            inter_zone_delay_seconds: Some(30),
+            catch_up_minutes: None,
+        });
+        hasher.zone(&ZoneAction {
+            zone_ids: vec!["aa:bb/1".into(), "aa:bb/2".into()],
+            duration_seconds: 600,
+            cycles: Some(3),
+            soak_seconds: Some(300),
+        });
+        hasher.program(&ProgramLine {
+            id: "p2",
+            name: "Garden",
+            weekdays: &[7],
+            start_time: "21:00:00",
+            active: false,
+            water_budget: Some(120),
+            inter_zone_delay_seconds: None,
+            catch_up_minutes: Some(45),
+        });
+        hasher.zone(&ZoneAction {
+            zone_ids: vec!["aa:bb/3".into()],
+            duration_seconds: 900,
+            cycles: None,
+            soak_seconds: None,
+        });
+        assert_eq!(hasher.finish(), "84d4ebb86623eab0");
+    }
+}
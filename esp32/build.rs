@@ -11,4 +11,14 @@ fn main() {
             println!("cargo:rustc-env={}={}", key.trim(), value.trim());
         }
     }
+
+    // Build hash reported in the hello
+    let build_hash = std::process::Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=BUILD_HASH={}", build_hash);
 }
@@ -2,40 +2,103 @@ use chrono::{TimeZone, Utc};
 use esp_idf_svc::wifi::{AsyncWifi, EspWifi};
 use serde::Serialize;
 
-use crate::{get_mac, relay::RelayController, BoardEvent, ZoneAction};
+use crate::{
+    get_mac,
+    relay::{RecoveryOutcome, RecoveryPolicy},
+    BoardEvent, ProgramProgress, RunState, ZoneAction,
+};
+
+// Event which the server records, sent once with the BoardInfo it happened in
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum DeviceEvent {
+    // A relay was force closed after its maximum runtime
+    SafetyCutoff {
+        relay_id: String,
+        open_seconds: u64,
+        max_seconds: u32,
+    },
+    // A program start was missed, it may have been run late
+    RunMissed {
+        program_id: String,
+        scheduled_at: String,
+        caught_up: bool,
+    },
+    // A program was interrupted by a reboot
+    RunRecovered {
+        program_id: String,
+        zone_index: usize,
+        policy: RecoveryPolicy,
+        outcome: RecoveryOutcome,
+    },
+    // Download or check of a firmware update failed
+    FirmwareUpdateFailed {
+        version: String,
+        error: String,
+    },
+    // The new firmware did not reconnect, the previous image was booted
+    FirmwareRolledBack {
+        version: String,
+    },
+    // The board could not use the server it was moved to
+    ServerConfigRejected {
+        url: String,
+    },
+    // Relay layout from the server failed validation on the board
+    RelayLayoutRejected {
+        error: String,
+    },
+    // Relay outputs could not be written, the valves may not be where they should be
+    RelayWriteFailed {
+        error: String,
+    },
+}
 
 #[derive(Serialize, Default, Clone)]
 pub struct BoardInfo {
     device_id: String,
     datetime: String,
     schedule_version: i32,
+    // Content hash of the schedule stored in NVS
+    schedule_hash: Option<String>,
     running_program: Option<String>,
     running_zones: Option<ZoneAction>,
+    progress: Option<ProgramProgress>,
+    run_state: RunState,
+    // Auto-resume time of the paused program
+    resume_at: Option<String>,
+    water_budget: Option<u16>,
+    suspended_until: Option<String>,
     zones: Vec<String>,
     log: Option<String>,
+    event: Option<DeviceEvent>,
 }
 
 impl BoardInfo {
     pub fn init(
         wifi: &AsyncWifi<EspWifi<'static>>,
-        relay_controller: &RelayController,
+        zones: Vec<String>,
         schedule_version: i32,
     ) -> Self {
         // Get the MAC address of the device
         let device_id = get_mac(wifi).unwrap();
         // Create a new BoardInfo instance
         let datetime = Utc::now().to_rfc3339();
-        // Init zones from relay controller
-        let zones = relay_controller.get_zones();
-
         Self {
             device_id,
             datetime,
             schedule_version,
+            schedule_hash: None,
             running_program: None,
             running_zones: None,
+            progress: None,
+            run_state: RunState::Idle,
+            resume_at: None,
+            water_budget: None,
+            suspended_until: None,
             zones,
             log: None,
+            event: None,
         }
     }
 
@@ -44,6 +107,8 @@ impl BoardInfo {
     pub fn apply_event(&mut self, event: &BoardEvent) -> Option<Self> {
         // Update the datetime to the current time
         self.datetime = Utc::now().to_rfc3339();
+        // Events are sent only once
+        self.event = None;
 
         // Match the event and update the BoardInfo accordingly
         match event {
@@ -56,13 +121,16 @@ impl BoardInfo {
                 Some(self.clone())
             }
             BoardEvent::WsStatusChanged { connected: _ } => None,
+            BoardEvent::HealthTick => None,
+            BoardEvent::FirmwareChunkRequested { .. } => None,
             BoardEvent::WifiStatusChanged { connected: _ } => None,
             BoardEvent::ServerCommandArrived { command: _ } => None,
             // Board stored new schedule
-            // Update schedule version
-            BoardEvent::ScheduleUpdated { version } => {
-                if self.schedule_version != *version {
+            // Update schedule version and hash
+            BoardEvent::ScheduleUpdated { version, hash } => {
+                if self.schedule_version != *version || self.schedule_hash != *hash {
                     self.schedule_version = *version;
+                    self.schedule_hash = hash.clone();
                     self.log = Some(format!("Schedule updated to version {}", version));
                     Some(self.clone()) // új állapot, küldeni kell
                 } else {
@@ -71,27 +139,40 @@ impl BoardInfo {
             }
             // Board has just started
             // Update schedule version based on the nvr stored schedule
-            BoardEvent::ScheduleLoaded { version } => {
-                if self.schedule_version != *version {
+            BoardEvent::ScheduleLoaded { version, hash } => {
+                if self.schedule_version != *version || self.schedule_hash != *hash {
                     self.schedule_version = *version;
+                    self.schedule_hash = hash.clone();
                     self.log = Some(format!("Schedule loaded from NVR to version {}", version));
                     Some(self.clone()) // új állapot, küldeni kell
                 } else {
                     None // nincs változás, ne küldjük újra
                 }
             }
-            BoardEvent::ProgramStarted { program: _ } => None,
+            BoardEvent::ProgramStarted { .. } => None,
             // Board started a program
-            // Update running program
-            BoardEvent::ProgramRunning { program } => {
+            // Update running program and its effective water budget
+            BoardEvent::ProgramRunning {
+                program,
+                water_budget,
+            } => {
                 self.running_program = Some(program.id.clone());
-                self.log = Some(format!("Program started: {}", program.name));
+                self.water_budget = Some(*water_budget);
+                // A new program replaces a paused one
+                self.resume_at = None;
+                self.log = Some(format!(
+                    "Program started: {} (water budget {}%)",
+                    program.name, water_budget
+                ));
                 Some(self.clone())
             }
             // Board stopped a program
             // Update running program
             BoardEvent::ProgramStopped => {
                 self.running_program = None;
+                self.progress = None;
+                self.resume_at = None;
+                self.water_budget = None;
                 self.log = Some("Program stopped".to_string());
                 Some(self.clone())
             }
@@ -117,6 +198,177 @@ impl BoardInfo {
                 self.log = Some("Zone action stopped".to_string());
                 Some(self.clone())
             }
+            // Board refused to open a zone action
+            // because of the relay constraints
+            BoardEvent::ZoneActionRejected {
+                zone_action,
+                reason,
+            } => {
+                self.log = Some(format!(
+                    "Zone action rejected: {} ({})",
+                    zone_action.zone_ids.join(", "),
+                    reason
+                ));
+                Some(self.clone())
+            }
+            // Relay config changed
+            // Update zones, the master valve is not a zone
+            BoardEvent::ZonesChanged { zones } => {
+                if self.zones != *zones {
+                    self.zones = zones.clone();
+                    self.log = Some("Zones updated".to_string());
+                    Some(self.clone())
+                } else {
+                    None
+                }
+            }
+            // Relay module state changed
+            // Update run state, the log keeps the last event
+            BoardEvent::RunStateChanged { state } => {
+                if self.run_state != *state {
+                    self.run_state = *state;
+                    Some(self.clone())
+                } else {
+                    None
+                }
+            }
+            // Board paused the running program
+            // Update auto-resume time
+            BoardEvent::ProgramPaused { resume_at } => {
+                self.resume_at = resume_at.map(|r| r.to_rfc3339());
+                self.log = Some(match resume_at {
+                    Some(r) => format!("Program paused until {}", r.to_rfc3339()),
+                    None => "Program paused".to_string(),
+                });
+                Some(self.clone())
+            }
+            BoardEvent::ProgramResumed => {
+                self.resume_at = None;
+                self.log = Some("Program resumed".to_string());
+                Some(self.clone())
+            }
+            // Program moved to another step, paused or the current zone was extended
+            // Update progress
+            BoardEvent::ProgressChanged { progress } => {
+                self.progress = Some(progress.clone());
+                Some(self.clone())
+            }
+            // Board force closed a relay
+            // Report it as a safety event
+            BoardEvent::SafetyCutoff {
+                relay_id,
+                open_seconds,
+                max_seconds,
+            } => {
+                self.log = Some(format!(
+                    "Safety cutoff: relay {} closed after {} seconds",
+                    relay_id, open_seconds
+                ));
+                self.event = Some(DeviceEvent::SafetyCutoff {
+                    relay_id: relay_id.clone(),
+                    open_seconds: *open_seconds,
+                    max_seconds: *max_seconds,
+                });
+                Some(self.clone())
+            }
+            // Board missed a program start
+            // Report it as an event
+            BoardEvent::RunMissed {
+                program_id,
+                scheduled_at,
+                caught_up,
+            } => {
+                self.log = Some(format!(
+                    "Missed run of program {} at {}{}",
+                    program_id,
+                    scheduled_at.to_rfc3339(),
+                    if *caught_up { ", started late" } else { "" }
+                ));
+                self.event = Some(DeviceEvent::RunMissed {
+                    program_id: program_id.clone(),
+                    scheduled_at: scheduled_at.to_rfc3339(),
+                    caught_up: *caught_up,
+                });
+                Some(self.clone())
+            }
+            BoardEvent::RunInterrupted { .. } => None,
+            BoardEvent::RunRecoveryReady { .. } => None,
+            // Board applied the recovery policy to a program interrupted by a reboot
+            // Report it as an event
+            BoardEvent::RunRecovered {
+                program_id,
+                zone_index,
+                policy,
+                outcome,
+            } => {
+                self.log = Some(format!(
+                    "Program {} interrupted at zone {}: {:?}",
+                    program_id, zone_index, outcome
+                ));
+                self.event = Some(DeviceEvent::RunRecovered {
+                    program_id: program_id.clone(),
+                    zone_index: *zone_index,
+                    policy: *policy,
+                    outcome: *outcome,
+                });
+                Some(self.clone())
+            }
+            // Rain delay set, cleared or expired
+            // Update suspended until
+            BoardEvent::RainDelayChanged { until } => {
+                self.suspended_until = until.map(|u| u.to_rfc3339());
+                self.log = Some(match until {
+                    Some(u) => format!("Irrigation suspended until {}", u.to_rfc3339()),
+                    None => "Irrigation resumed".to_string(),
+                });
+                Some(self.clone())
+            }
+            // Report firmware update problems as events
+            BoardEvent::FirmwareUpdateFailed { version, error } => {
+                self.log = Some(format!("Firmware update to {} failed: {}", version, error));
+                self.event = Some(DeviceEvent::FirmwareUpdateFailed {
+                    version: version.clone(),
+                    error: error.clone(),
+                });
+                Some(self.clone())
+            }
+            BoardEvent::FirmwareRolledBack { version } => {
+                self.log = Some(format!("Firmware {} rolled back", version));
+                self.event = Some(DeviceEvent::FirmwareRolledBack {
+                    version: version.clone(),
+                });
+                Some(self.clone())
+            }
+            // Reported to the previous server once the board is back
+            BoardEvent::ServerConfigRejected { url } => {
+                self.log = Some(format!(
+                    "Server {} did not answer, using the previous one",
+                    url
+                ));
+                self.event = Some(DeviceEvent::ServerConfigRejected { url: url.clone() });
+                Some(self.clone())
+            }
+            BoardEvent::RelayLayoutSaved { relays } => {
+                self.log = Some(format!(
+                    "Relay layout with {} relays saved, applied at the next boot",
+                    relays
+                ));
+                Some(self.clone())
+            }
+            BoardEvent::RelayLayoutRejected { error } => {
+                self.log = Some(format!("Relay layout rejected: {}", error));
+                self.event = Some(DeviceEvent::RelayLayoutRejected {
+                    error: error.clone(),
+                });
+                Some(self.clone())
+            }
+            BoardEvent::RelayWriteFailed { error } => {
+                self.log = Some(format!("Failed to switch relays: {}", error));
+                self.event = Some(DeviceEvent::RelayWriteFailed {
+                    error: error.clone(),
+                });
+                Some(self.clone())
+            }
         }
     }
 }
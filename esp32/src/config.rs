@@ -0,0 +1,279 @@
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+use esp_idf_svc::wifi::AuthMethod;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::io::BufRead;
+use std::thread;
+use std::time::Duration;
+
+// NVS namespace of the provisioned settings
+pub const NAMESPACE: &str = "config";
+
+// Networks the board may join
+pub const MAX_WIFI_NETWORKS: usize = 5;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum WifiAuth {
+    Open,
+    Wpa2Personal,
+    WpaWpa2Personal,
+    Wpa3Personal,
+    Wpa2Wpa3Personal,
+}
+
+impl WifiAuth {
+    pub fn method(self) -> AuthMethod {
+        match self {
+            WifiAuth::Open => AuthMethod::None,
+            WifiAuth::Wpa2Personal => AuthMethod::WPA2Personal,
+            WifiAuth::WpaWpa2Personal => AuthMethod::WPAWPA2Personal,
+            WifiAuth::Wpa3Personal => AuthMethod::WPA3Personal,
+            WifiAuth::Wpa2Wpa3Personal => AuthMethod::WPA2WPA3Personal,
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "open" => Some(WifiAuth::Open),
+            "wpa2" => Some(WifiAuth::Wpa2Personal),
+            "wpa/wpa2" | "wpawpa2" => Some(WifiAuth::WpaWpa2Personal),
+            "wpa3" => Some(WifiAuth::Wpa3Personal),
+            "wpa2/wpa3" | "wpa2wpa3" => Some(WifiAuth::Wpa2Wpa3Personal),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct WifiNetwork {
+    pub ssid: String,
+    pub password: String,
+    // None picks open or WPA2 by the password
+    #[serde(default)]
+    pub auth: Option<WifiAuth>,
+}
+
+impl WifiNetwork {
+    pub fn auth_method(&self) -> AuthMethod {
+        match self.auth {
+            Some(auth) => auth.method(),
+            None if self.password.is_empty() => AuthMethod::None,
+            None => AuthMethod::WPA2Personal,
+        }
+    }
+}
+
+// Server the board connects to
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ServerConfig {
+    pub url: String,
+    pub token: String,
+}
+
+pub fn open_nvs(esp_partition: EspNvsPartition<NvsDefault>) -> anyhow::Result<EspNvs<NvsDefault>> {
+    Ok(EspNvs::new(esp_partition, NAMESPACE, true)?)
+}
+
+// Stored networks in order of preference, the network given at build time if nothing is provisioned
+pub fn load_wifi(nvs: &EspNvs<NvsDefault>) -> Vec<WifiNetwork> {
+    let stored = match load_json::<Vec<WifiNetwork>>(nvs, "wifi_nets") {
+        Ok(None) => load_json::<WifiNetwork>(nvs, "wifi_cfg").map(|n| n.map(|n| vec![n])),
+        other => other,
+    };
+    match stored {
+        Ok(Some(networks)) => networks,
+        Ok(None) => match option_env!("WIFI_SSID") {
+            Some(ssid) => vec![WifiNetwork {
+                ssid: ssid.to_string(),
+                password: option_env!("WIFI_PASS").unwrap_or_default().to_string(),
+                auth: None,
+            }],
+            None => Vec::new(),
+        },
+        Err(e) => {
+            info!("Failed to load Wi-Fi config from NVS: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+pub fn save_wifi(nvs: &mut EspNvs<NvsDefault>, networks: &[WifiNetwork]) -> anyhow::Result<()> {
+    nvs.set_str("wifi_nets", &serde_json::to_string(networks)?)?;
+    // Single network stored by older firmware
+    nvs.remove("wifi_cfg")?;
+    Ok(())
+}
+
+// Stored server settings, the values given at build time if nothing is provisioned
+pub fn load_server(nvs: &EspNvs<NvsDefault>) -> ServerConfig {
+    match load_json(nvs, "server_cfg") {
+        Ok(Some(config)) => config,
+        Ok(None) => ServerConfig {
+            url: option_env!("WS_URL").unwrap_or_default().to_string(),
+            token: option_env!("WS_AUTH_TOKEN").unwrap_or_default().to_string(),
+        },
+        Err(e) => {
+            info!("Failed to load server config from NVS: {}", e);
+            ServerConfig::default()
+        }
+    }
+}
+
+pub fn save_server(nvs: &mut EspNvs<NvsDefault>, config: &ServerConfig) -> anyhow::Result<()> {
+    nvs.set_str("server_cfg", &serde_json::to_string(config)?)?;
+    Ok(())
+}
+
+fn load_json<T: serde::de::DeserializeOwned>(
+    nvs: &EspNvs<NvsDefault>,
+    key: &str,
+) -> anyhow::Result<Option<T>> {
+    let mut buf = vec![0u8; 2048];
+    match nvs.get_str(key, &mut buf)? {
+        Some(data) => Ok(Some(serde_json::from_str(data)?)),
+        None => Ok(None),
+    }
+}
+
+const CONSOLE_HELP: &str = "Commands:
+  wifi add <ssid> [password] [auth]   add or update a Wi-Fi network, auth is
+                                      open, wpa2, wpa/wpa2, wpa3 or wpa2/wpa3
+  wifi remove <ssid>                  remove a Wi-Fi network
+  wifi clear                          remove every Wi-Fi network
+  server <url> <token>                set the websocket server
+  show                                print the settings
+  reboot                              restart the board
+Networks are tried strongest first, the order breaks ties.
+Quote values with spaces, e.g. wifi add \"My Garden\" secret";
+
+// Provisioning console on the serial port
+// Settings are written to NVS and used on the next connect.
+pub fn start_console(esp_partition: EspNvsPartition<NvsDefault>) -> anyhow::Result<()> {
+    let mut nvs = open_nvs(esp_partition)?;
+    thread::Builder::new()
+        .name("console".into())
+        .stack_size(6144)
+        .spawn(move || {
+            let stdin = std::io::stdin();
+            let mut line = String::new();
+            loop {
+                // The UART console does not block, wait for the rest of the line
+                match stdin.lock().read_line(&mut line) {
+                    Ok(_) if line.ends_with('\n') => {
+                        run_console_command(&mut nvs, line.trim());
+                        line.clear();
+                    }
+                    _ => thread::sleep(Duration::from_millis(100)),
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn run_console_command(nvs: &mut EspNvs<NvsDefault>, line: &str) {
+    let args = split_args(line);
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        [] => (),
+        ["wifi", "add", ssid] => console_add_wifi(nvs, ssid, "", None),
+        ["wifi", "add", ssid, password] => console_add_wifi(nvs, ssid, password, None),
+        ["wifi", "add", ssid, password, auth] => match WifiAuth::parse(auth) {
+            Some(auth) => console_add_wifi(nvs, ssid, password, Some(auth)),
+            None => println!("Unknown auth method {}", auth),
+        },
+        ["wifi", "remove", ssid] => {
+            let mut networks = load_wifi(nvs);
+            networks.retain(|n| n.ssid != *ssid);
+            console_save_wifi(nvs, &networks);
+        }
+        ["wifi", "clear"] => console_save_wifi(nvs, &[]),
+        ["server", url, token] => {
+            if !url.starts_with("ws://") && !url.starts_with("wss://") {
+                println!("The server url has to start with ws:// or wss://");
+                return;
+            }
+            let config = ServerConfig {
+                url: url.to_string(),
+                token: token.to_string(),
+            };
+            match save_server(nvs, &config) {
+                Ok(()) => println!("Server saved, used on the next connect"),
+                Err(e) => println!("Failed to save server: {}", e),
+            }
+        }
+        ["show"] => {
+            for (i, network) in load_wifi(nvs).iter().enumerate() {
+                println!(
+                    "wifi {}: {} ({:?})",
+                    i + 1,
+                    network.ssid,
+                    network.auth_method()
+                );
+            }
+            println!("server url: {}", load_server(nvs).url);
+        }
+        ["reboot"] => esp_idf_svc::hal::reset::restart(),
+        _ => println!("{}", CONSOLE_HELP),
+    }
+}
+
+// Add a network at the end of the list, a known network keeps its place
+fn console_add_wifi(
+    nvs: &mut EspNvs<NvsDefault>,
+    ssid: &str,
+    password: &str,
+    auth: Option<WifiAuth>,
+) {
+    let network = WifiNetwork {
+        ssid: ssid.to_string(),
+        password: password.to_string(),
+        auth,
+    };
+    let mut networks = load_wifi(nvs);
+    match networks.iter_mut().find(|n| n.ssid == ssid) {
+        Some(existing) => *existing = network,
+        None if networks.len() >= MAX_WIFI_NETWORKS => {
+            println!("At most {} networks can be stored", MAX_WIFI_NETWORKS);
+            return;
+        }
+        None => networks.push(network),
+    }
+    console_save_wifi(nvs, &networks);
+}
+
+fn console_save_wifi(nvs: &mut EspNvs<NvsDefault>, networks: &[WifiNetwork]) {
+    match save_wifi(nvs, networks) {
+        Ok(()) => println!("Wi-Fi saved, used on the next connect"),
+        Err(e) => println!("Failed to save Wi-Fi: {}", e),
+    }
+}
+
+// Split a console line on whitespace, double quotes keep spaces in a value
+fn split_args(line: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut started = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                started = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if started {
+                    args.push(std::mem::take(&mut current));
+                    started = false;
+                }
+            }
+            c => {
+                current.push(c);
+                started = true;
+            }
+        }
+    }
+    if started {
+        args.push(current);
+    }
+    args
+}
@@ -0,0 +1,75 @@
+use chrono::{DateTime, Utc};
+use crossbeam::channel::Sender;
+use ds3231::DS3231;
+use esp_idf_svc::hal::reset::ResetReason;
+use esp_idf_svc::sntp::SyncStatus;
+use log::info;
+use serde::Serialize;
+use std::thread;
+use std::time::Duration;
+
+use crate::{wifi, BoardEvent, I2cBus};
+
+// How often health data is reported
+const HEALTH_INTERVAL: Duration = Duration::from_secs(60);
+
+// Health data of the board, sent periodically to the server
+#[derive(Serialize, Debug, Clone)]
+pub struct Health {
+    datetime: DateTime<Utc>,
+    // Network and access point the board is connected to, None if not connected
+    wifi_ssid: Option<String>,
+    wifi_bssid: Option<String>,
+    rssi: Option<i8>,
+    free_heap: u32,
+    // Lowest free heap since boot
+    min_free_heap: u32,
+    uptime_seconds: u64,
+    reset_reason: String,
+    sntp_status: String,
+    // DS3231 die temperature in Celsius
+    rtc_temperature: Option<f32>,
+}
+
+// Send a health tick periodically to the main loop
+pub fn start_ticker(tx: Sender<BoardEvent>) {
+    thread::Builder::new()
+        .name("health_ticker".into())
+        .stack_size(2048)
+        .spawn(move || loop {
+            thread::sleep(HEALTH_INTERVAL);
+            if tx.send(BoardEvent::HealthTick).is_err() {
+                break;
+            }
+        })
+        .expect("Failed to spawn health ticker thread");
+}
+
+// Collect the health data of the board
+pub fn collect(rtc: &mut DS3231<I2cBus>, sntp_status: SyncStatus) -> Health {
+    let rtc_temperature = match rtc.temperature() {
+        Ok(temperature) => Some(temperature),
+        Err(e) => {
+            info!("Failed to read DS3231 temperature: {:?}", e);
+            None
+        }
+    };
+    let ap = wifi::connected_ap();
+    Health {
+        datetime: Utc::now(),
+        wifi_ssid: ap.as_ref().map(|ap| ap.ssid.clone()),
+        wifi_bssid: ap.as_ref().map(|ap| wifi::format_bssid(&ap.bssid)),
+        rssi: ap.as_ref().map(|ap| ap.rssi),
+        free_heap: unsafe { esp_idf_svc::sys::esp_get_free_heap_size() },
+        min_free_heap: unsafe { esp_idf_svc::sys::esp_get_minimum_free_heap_size() },
+        uptime_seconds: (unsafe { esp_idf_svc::sys::esp_timer_get_time() } / 1_000_000) as u64,
+        reset_reason: format!("{:?}", ResetReason::get()),
+        sntp_status: match sntp_status {
+            SyncStatus::Reset => "Reset",
+            SyncStatus::InProgress => "InProgress",
+            SyncStatus::Completed => "Completed",
+        }
+        .to_string(),
+        rtc_temperature,
+    }
+}
@@ -1,10 +1,9 @@
 use boardinfo::BoardInfo;
-use chrono::{NaiveDateTime, NaiveTime, Utc};
+use chrono::{DateTime, NaiveDateTime, NaiveTime, Utc};
 use ds3231::{
     Config as DsConfig, InterruptControl, Oscillator, SquareWaveFrequency, TimeRepresentation,
     DS3231,
 };
-use esp_idf_svc::hal::gpio::PinDriver;
 use esp_idf_svc::hal::i2c::config::Config as I2cConfig;
 use esp_idf_svc::hal::i2c::I2cDriver;
 use esp_idf_svc::hal::peripherals::Peripherals;
@@ -14,16 +13,13 @@ use esp_idf_svc::timer::EspTaskTimerService;
 use esp_idf_svc::wifi::{AsyncWifi, EspWifi};
 use esp_idf_svc::{eventloop::EspSystemEventLoop, nvs::EspDefaultNvsPartition};
 use log::info;
-use relay::{Relay, RelayController};
+use relay::{
+    RecoveryOutcome, RecoveryPolicy, Relay, RelayConfig, RelayController, RelayLayout, RunJournal,
+};
 use serde::{Deserialize, Serialize};
 use std::thread::{self};
 use std::time::Duration;
 
-const WS_URL: &str = env!("WS_URL");
-const WS_AUTH_TOKEN: &str = env!("WS_AUTH_TOKEN");
-const SSID: &str = env!("WIFI_SSID");
-const PASSWORD: &str = env!("WIFI_PASS");
-
 // const RELAY_PIN_MAP: [(i32, i32); 8] = [
 //     (1, 2),
 //     (2, 4),
@@ -36,17 +32,16 @@ const PASSWORD: &str = env!("WIFI_PASS");
 // ];
 
 mod boardinfo;
+mod config;
+mod health;
+mod ota;
 mod relay;
 mod schedule;
 mod time;
 mod wifi;
 mod ws;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct ZoneAction {
-    zone_ids: Vec<String>,
-    duration_seconds: i32,
-}
+pub use board_core::plan::ZoneAction;
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Program {
@@ -56,12 +51,42 @@ pub struct Program {
     start_time: NaiveTime,
     active: bool,
     zones: Vec<ZoneAction>,
+    // Per-program water budget in percent, overrides the global one
+    #[serde(default)]
+    water_budget: Option<u16>,
+    // Delay between sequential zones, overrides the board level one
+    #[serde(default)]
+    inter_zone_delay_seconds: Option<u32>,
+    // A missed start is run late if it is less than this many minutes late
+    #[serde(default)]
+    catch_up_minutes: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Schedule {
     version: i32,
     programs: Vec<Program>,
+    #[serde(default)]
+    water_budget: WaterBudget,
+}
+
+// Global water budget in percent
+// If monthly contains 12 values (January first),
+// the value of the current month is used instead of percent.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WaterBudget {
+    percent: u16,
+    #[serde(default)]
+    monthly: Vec<u16>,
+}
+
+impl Default for WaterBudget {
+    fn default() -> Self {
+        Self {
+            percent: 100,
+            monthly: vec![],
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -70,21 +95,204 @@ pub enum ServerCommand {
     Stop,
     StartZoneAction(ZoneAction),
     StartProgram(String),
+    // Suspend automatic program starts until the given time, None clears it
+    SetRainDelay(Option<DateTime<Utc>>),
+    SetRelayConfig {
+        device_id: String,
+        config: RelayConfig,
+    },
+    // Pause the running program, resume automatically after the given seconds
+    Pause(Option<u32>),
+    Resume,
+    SkipZone,
+    ExtendZone {
+        seconds: u32,
+    },
+    // Answer to the hello, with the protocol version the server speaks
+    Welcome {
+        protocol_version: u32,
+    },
+    // Maintenance commands, addressed to one board
+    // The server only sends them once a token was confirmed there, the board has nothing to check.
+    Reboot {
+        device_id: String,
+    },
+    // Erase the stored schedule
+    ClearSchedule {
+        device_id: String,
+    },
+    // Erase the schedule, relay and update state and restart,
+    // Wi-Fi and server settings are kept
+    FactoryReset {
+        device_id: String,
+    },
+    // Download and install a firmware release
+    UpdateFirmware {
+        device_id: String,
+        version: String,
+        size: u64,
+        sha256: String,
+    },
+    // Part of the firmware image, answer to a chunk request
+    FirmwareChunk {
+        version: String,
+        offset: u64,
+        data: String,
+    },
+    // Connect to another server, the board falls back if it does not answer
+    SetServerConfig {
+        device_id: String,
+        url: String,
+        token: String,
+    },
+    // Relay layout used from the next boot
+    SetRelayLayout {
+        device_id: String,
+        layout: RelayLayout,
+    },
+}
+
+// State of the relay module
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum RunState {
+    #[default]
+    Idle,
+    // Zones are open
+    Running,
+    // Inter-zone delay or master valve lead time
+    Waiting,
+    // Soak pause of cycle-and-soak zones
+    Soaking,
+    Paused,
+}
+
+// Position and timing of the running program
+// Zone index counts the zone actions of the program from zero,
+// cycle counts the cycles of the zone action from one.
+// It is the only report of the zone end, skipping or extending a zone sends it again.
+#[derive(Serialize, Debug, Clone)]
+pub struct ProgramProgress {
+    zone_index: usize,
+    zone_count: usize,
+    cycle: u32,
+    cycles: u32,
+    zone_started_at: Option<DateTime<Utc>>,
+    zone_ends_at: Option<DateTime<Utc>>,
+    program_ends_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Clone)]
 pub enum BoardEvent {
-    ScheduleUpdated { version: i32 },
-    ScheduleLoaded { version: i32 },
-    ProgramStarted { program: Program },
-    ProgramRunning { program: Program },
+    // Hash of the schedule read back from NVS, None if it could not be read
+    ScheduleUpdated {
+        version: i32,
+        hash: Option<String>,
+    },
+    ScheduleLoaded {
+        version: i32,
+        hash: Option<String>,
+    },
+    ProgramStarted {
+        program: Program,
+        water_budget: u16,
+    },
+    ProgramRunning {
+        program: Program,
+        water_budget: u16,
+    },
     ProgramStopped,
-    ZoneActionStarted { zone_action: ZoneAction },
+    ZoneActionStarted {
+        zone_action: ZoneAction,
+    },
     ZoneActionStopped,
-    DateTimeUpdated { time: NaiveDateTime },
-    WsStatusChanged { connected: bool },
-    WifiStatusChanged { connected: bool },
-    ServerCommandArrived { command: ServerCommand },
+    ZoneActionRejected {
+        zone_action: ZoneAction,
+        reason: String,
+    },
+    ZonesChanged {
+        zones: Vec<String>,
+    },
+    RunStateChanged {
+        state: RunState,
+    },
+    ProgramPaused {
+        resume_at: Option<DateTime<Utc>>,
+    },
+    ProgramResumed,
+    ProgressChanged {
+        progress: ProgramProgress,
+    },
+    SafetyCutoff {
+        relay_id: String,
+        open_seconds: u64,
+        max_seconds: u32,
+    },
+    DateTimeUpdated {
+        time: NaiveDateTime,
+    },
+    WsStatusChanged {
+        connected: bool,
+    },
+    WifiStatusChanged {
+        connected: bool,
+    },
+    ServerCommandArrived {
+        command: ServerCommand,
+    },
+    RainDelayChanged {
+        until: Option<DateTime<Utc>>,
+    },
+    // Program start missed while the board was off or its clock was wrong
+    RunMissed {
+        program_id: String,
+        scheduled_at: DateTime<Utc>,
+        caught_up: bool,
+    },
+    // Run journal found at boot
+    RunInterrupted {
+        journal: RunJournal,
+    },
+    // Program of the run journal looked up in the schedule
+    RunRecoveryReady {
+        journal: RunJournal,
+        program: Option<Program>,
+    },
+    RunRecovered {
+        program_id: String,
+        zone_index: usize,
+        policy: RecoveryPolicy,
+        outcome: RecoveryOutcome,
+    },
+    // Time to report the health of the board
+    HealthTick,
+    // OTA module needs the next part of the image
+    FirmwareChunkRequested {
+        version: String,
+        offset: u64,
+    },
+    FirmwareUpdateFailed {
+        version: String,
+        error: String,
+    },
+    // Found at boot, the previous firmware was booted again
+    FirmwareRolledBack {
+        version: String,
+    },
+    // New server did not answer, the previous one is used again
+    ServerConfigRejected {
+        url: String,
+    },
+    // New relay layout stored, applied at the next boot
+    RelayLayoutSaved {
+        relays: usize,
+    },
+    RelayLayoutRejected {
+        error: String,
+    },
+    // Relay outputs could not be written
+    RelayWriteFailed {
+        error: String,
+    },
 }
 
 // Set system time from NaiveDateTime
@@ -142,9 +350,12 @@ fn get_mac(wifi: &AsyncWifi<EspWifi<'static>>) -> anyhow::Result<String> {
     Ok(mac_str)
 }
 
+// I2C bus of the DS3231 and the GPIO expanders
+pub type I2cBus = board_core::expander::SharedI2c<I2cDriver<'static>>;
+
 // Read time from DS3231
 // This function reads the current date and time from the DS3231 RTC.
-fn get_dtime_from_ds3231(rtc: &mut DS3231<I2cDriver>) -> anyhow::Result<chrono::NaiveDateTime> {
+fn get_dtime_from_ds3231(rtc: &mut DS3231<I2cBus>) -> anyhow::Result<chrono::NaiveDateTime> {
     // Get current date/time
     let datetime = rtc.datetime().unwrap();
     Ok(datetime.into())
@@ -153,7 +364,7 @@ fn get_dtime_from_ds3231(rtc: &mut DS3231<I2cDriver>) -> anyhow::Result<chrono::
 // Set date/time to DS3231
 // This function sets the date and time on the DS3231 RTC.
 fn set_dtime_to_ds3231(
-    rtc: &mut DS3231<I2cDriver>,
+    rtc: &mut DS3231<I2cBus>,
     datetime: chrono::NaiveDateTime,
 ) -> anyhow::Result<()> {
     rtc.set_datetime(&datetime).unwrap();
@@ -164,6 +375,30 @@ fn set_dtime_to_ds3231(
 //     ((bcd >> 4) * 10) + (bcd & 0x0F)
 // }
 
+// NVS namespaces erased by a factory reset
+// The config namespace is kept, the board needs its Wi-Fi and server settings to come back.
+const FACTORY_RESET_NAMESPACES: [&str; 3] = ["storage", "relay", "ota"];
+
+// Erase every key of an NVS namespace
+fn erase_nvs_namespace(namespace: &str) -> Result<(), esp_idf_svc::sys::EspError> {
+    use esp_idf_svc::sys::{
+        esp, nvs_close, nvs_commit, nvs_erase_all, nvs_handle_t, nvs_open,
+        nvs_open_mode_t_NVS_READWRITE,
+    };
+    let name = std::ffi::CString::new(namespace).unwrap();
+    let mut handle: nvs_handle_t = 0;
+    unsafe {
+        esp!(nvs_open(
+            name.as_ptr(),
+            nvs_open_mode_t_NVS_READWRITE,
+            &mut handle
+        ))?;
+        let res = esp!(nvs_erase_all(handle)).and_then(|_| esp!(nvs_commit(handle)));
+        nvs_close(handle);
+        res
+    }
+}
+
 fn main() -> anyhow::Result<()> {
     esp_idf_svc::sys::link_patches();
     EspLogger::initialize_default();
@@ -202,6 +437,8 @@ fn main() -> anyhow::Result<()> {
             ..Default::default()
         },
     )?;
+    // Shared by the DS3231 and the relay expanders
+    let i2c = I2cBus::new(i2c);
 
     let config = DsConfig {
         time_representation: TimeRepresentation::TwentyFourHour,
@@ -213,7 +450,7 @@ fn main() -> anyhow::Result<()> {
 
     info!("Initializing DS3231...");
 
-    let mut rtc = DS3231::new(i2c, 0x68);
+    let mut rtc = DS3231::new(i2c.clone(), 0x68);
 
     info!("DS3231 initialized");
 
@@ -231,45 +468,35 @@ fn main() -> anyhow::Result<()> {
 
     let mac = get_mac(&wifi)?;
 
-    let relay_pins: Vec<Relay> = vec![
-        Relay::new(
-            format!("{mac}/1"),
-            PinDriver::output(peripherals.pins.gpio2)?,
-        ),
-        Relay::new(
-            format!("{mac}/2"),
-            PinDriver::output(peripherals.pins.gpio4)?,
-        ),
-        Relay::new(
-            format!("{mac}/3"),
-            PinDriver::output(peripherals.pins.gpio5)?,
-        ),
-        Relay::new(
-            format!("{mac}/4"),
-            PinDriver::output(peripherals.pins.gpio25)?,
-        ),
-        Relay::new(
-            format!("{mac}/5"),
-            PinDriver::output(peripherals.pins.gpio26)?,
-        ),
-        Relay::new(
-            format!("{mac}/6"),
-            PinDriver::output(peripherals.pins.gpio18)?,
-        ),
-        Relay::new(
-            format!("{mac}/7"),
-            PinDriver::output(peripherals.pins.gpio19)?,
-        ),
-    ];
+    // Relay layout stored in NVS, applied at boot
+    let layout = relay::load_layout(default.clone());
+    info!("Relay layout: {:?}", layout);
+    // Reported once the event channel exists
+    let mut boot_events = Vec::new();
+    // A missing or broken expander must not keep the board from booting
+    let relay_pins: Vec<Relay> = match relay::build_relays(&mac, &layout, &i2c) {
+        Ok(relays) => relays,
+        Err(e) => {
+            info!("Relay layout failed, using the default one: {:?}", e);
+            boot_events.push(BoardEvent::RelayLayoutRejected {
+                error: e.to_string(),
+            });
+            relay::build_relays(&mac, &RelayLayout::default(), &i2c)?
+        }
+    };
+
+    let relay_count = relay_pins.len() as u32;
 
     // RelayController initialization
     let mut relay_controller = RelayController::new(relay_pins);
 
     // Close all relays initially
-    relay_controller.close_all();
-
-    // BoardInfo initialization
-    let mut boardinfo = BoardInfo::init(&wifi, &relay_controller, 0);
+    if let Err(e) = relay_controller.close_all() {
+        info!("Failed to close relays: {}", e);
+        boot_events.push(BoardEvent::RelayWriteFailed {
+            error: e.to_string(),
+        });
+    }
 
     // thread::spawn(move || loop {
     //     for i in 1..=7 {
@@ -285,9 +512,18 @@ fn main() -> anyhow::Result<()> {
     // });
 
     let (tx, rx) = crossbeam::channel::unbounded::<BoardEvent>();
+    for event in boot_events {
+        let _ = tx.send(event);
+    }
 
     // Init relay module
-    let (relay_module, relay_tx) = relay::RelayModule::new(relay_controller, tx.clone());
+    let (relay_module, relay_tx) =
+        relay::RelayModule::new(relay_controller, tx.clone(), default.clone());
+
+    // BoardInfo initialization
+    // Zones are known once the relay config is loaded
+    let mut boardinfo = BoardInfo::init(&wifi, relay_module.get_zones(), 0);
+
     // Start relay module
     relay_module.start();
 
@@ -299,11 +535,20 @@ fn main() -> anyhow::Result<()> {
     // Start schedule module
     schedule_module.start();
 
+    // Init OTA module
+    let (ota_module, ota_tx) = ota::OtaModule::new(tx.clone(), default.clone())?;
+
+    // Start OTA module
+    ota_module.start();
+
     let mac = get_mac(&wifi)?;
     info!("MAC Address: {}", mac);
 
+    // Serial console to provision Wi-Fi and server settings
+    config::start_console(default.clone())?;
+
     // Initialize WiFi
-    let (wifi_module, wifi_tx) = wifi::WifiModule::new(wifi, tx.clone())?;
+    let (wifi_module, wifi_tx) = wifi::WifiModule::new(wifi, tx.clone(), default.clone())?;
     // Start WiFi module
     wifi_module.start();
 
@@ -333,14 +578,30 @@ fn main() -> anyhow::Result<()> {
     let now = Utc::now().naive_utc();
     info!("Current UTC time from systime: {now}");
 
+    // Hello sent as the first frame of every connection
+    let hello = ws::Hello {
+        device_id: mac.clone(),
+        firmware_version: env!("CARGO_PKG_VERSION").to_string(),
+        build_hash: env!("BUILD_HASH").to_string(),
+        protocol_version: ws::PROTOCOL_VERSION,
+        relay_count,
+        capabilities: ws::Capabilities {
+            pause: true,
+            ..Default::default()
+        },
+    };
+
     // Init WsModule
-    let (ws_module, ws_tx) =
-        ws::WsModule::new(WS_URL.to_string(), WS_AUTH_TOKEN.to_string(), tx.clone());
+    // Server settings are read from NVS
+    let (ws_module, ws_tx) = ws::WsModule::new(hello, tx.clone(), default.clone())?;
 
     // Start WebSocket module
     ws_module.start();
     info!("WebSocket client started");
 
+    // Report health periodically
+    health::start_ticker(tx.clone());
+
     loop {
         match rx.recv() {
             Ok(event) => {
@@ -384,7 +645,6 @@ fn main() -> anyhow::Result<()> {
                         }
                     }
                     BoardEvent::ServerCommandArrived { command } => {
-                        info!("Server command arrived: {:?}", command);
                         match command {
                             ServerCommand::SetNewSchedule(schedule) => {
                                 info!("New schedule received: version={}", schedule.version);
@@ -406,18 +666,162 @@ fn main() -> anyhow::Result<()> {
                                 let _ = schedule_tx
                                     .send(schedule::ScheduleCommand::StartProgramById(program_id));
                             }
+                            ServerCommand::SetRainDelay(until) => {
+                                info!("SetRainDelay command received: {:?}", until);
+                                let _ = schedule_tx
+                                    .send(schedule::ScheduleCommand::SetRainDelay(until));
+                            }
+                            ServerCommand::SetRelayConfig { device_id, config } => {
+                                info!("SetRelayConfig command received: {:?}", config);
+                                if device_id == mac {
+                                    let _ = relay_tx.send(relay::RelayCommand::SetConfig(config));
+                                }
+                            }
+                            ServerCommand::Pause(auto_resume_seconds) => {
+                                info!("Pause command received: {:?}", auto_resume_seconds);
+                                let _ =
+                                    relay_tx.send(relay::RelayCommand::Pause(auto_resume_seconds));
+                            }
+                            ServerCommand::Resume => {
+                                info!("Resume command received");
+                                let _ = relay_tx.send(relay::RelayCommand::Resume);
+                            }
+                            ServerCommand::SkipZone => {
+                                info!("SkipZone command received");
+                                let _ = relay_tx.send(relay::RelayCommand::SkipZone);
+                            }
+                            ServerCommand::ExtendZone { seconds } => {
+                                info!("ExtendZone command received: {} seconds", seconds);
+                                let _ = relay_tx.send(relay::RelayCommand::ExtendZone(seconds));
+                            }
+                            ServerCommand::Welcome { protocol_version } => {
+                                info!("Server speaks protocol version {}", protocol_version);
+                                // The running firmware reached the server
+                                let _ = ota_tx.send(ota::OtaCommand::Confirm);
+                                let _ = ws_tx.send(ws::WsCommand::ServerConfirmed);
+                            }
+                            ServerCommand::Reboot { device_id } => {
+                                if device_id == mac {
+                                    info!("Reboot command received");
+                                    esp_idf_svc::hal::reset::restart();
+                                }
+                            }
+                            ServerCommand::ClearSchedule { device_id } => {
+                                if device_id == mac {
+                                    info!("ClearSchedule command received");
+                                    let _ =
+                                        schedule_tx.send(schedule::ScheduleCommand::ClearSchedule);
+                                }
+                            }
+                            ServerCommand::FactoryReset { device_id } => {
+                                if device_id == mac {
+                                    info!("FactoryReset command received");
+                                    for namespace in FACTORY_RESET_NAMESPACES {
+                                        if let Err(e) = erase_nvs_namespace(namespace) {
+                                            info!(
+                                                "Failed to erase NVS namespace {}: {}",
+                                                namespace, e
+                                            );
+                                        }
+                                    }
+                                    esp_idf_svc::hal::reset::restart();
+                                }
+                            }
+                            ServerCommand::UpdateFirmware {
+                                device_id,
+                                version,
+                                size,
+                                sha256,
+                            } => {
+                                if device_id == mac {
+                                    info!("UpdateFirmware command received: {}", version);
+                                    let _ = ota_tx.send(ota::OtaCommand::Update {
+                                        version,
+                                        size,
+                                        sha256,
+                                    });
+                                }
+                            }
+                            ServerCommand::FirmwareChunk {
+                                version,
+                                offset,
+                                data,
+                            } => {
+                                let _ = ota_tx.send(ota::OtaCommand::Chunk {
+                                    version,
+                                    offset,
+                                    data,
+                                });
+                            }
+                            ServerCommand::SetRelayLayout { device_id, layout } => {
+                                if device_id == mac {
+                                    info!("SetRelayLayout command received: {:?}", layout);
+                                    let _ = relay_tx.send(relay::RelayCommand::SetLayout(layout));
+                                }
+                            }
+                            ServerCommand::SetServerConfig {
+                                device_id,
+                                url,
+                                token,
+                            } => {
+                                if device_id == mac {
+                                    info!("SetServerConfig command received: {}", url);
+                                    let _ = ws_tx.send(ws::WsCommand::SetServer(
+                                        config::ServerConfig { url, token },
+                                    ));
+                                }
+                            }
                         }
                     }
-                    BoardEvent::ScheduleUpdated { version: _ } => (),
-                    BoardEvent::ScheduleLoaded { version: _ } => (),
-                    BoardEvent::ProgramStarted { program } => {
-                        info!("Program started: {}", program.name);
-                        let _ = relay_tx.send(relay::RelayCommand::StartProgram(program.clone()));
+                    BoardEvent::HealthTick => {
+                        let health = health::collect(&mut rtc, _sntp.get_sync_status());
+                        let _ = ws_tx.send(ws::WsCommand::Health(health));
+                    }
+                    BoardEvent::ScheduleUpdated { .. } => (),
+                    BoardEvent::ScheduleLoaded { .. } => (),
+                    BoardEvent::ProgramStarted {
+                        program,
+                        water_budget,
+                    } => {
+                        info!(
+                            "Program started: {} (water budget {}%)",
+                            program.name, water_budget
+                        );
+                        let _ = relay_tx.send(relay::RelayCommand::StartProgram(
+                            program.clone(),
+                            water_budget,
+                        ));
                     }
-                    BoardEvent::ProgramRunning { program: _ } => (),
+                    BoardEvent::ProgramRunning { .. } => (),
                     BoardEvent::ProgramStopped => (),
                     BoardEvent::ZoneActionStarted { zone_action: _ } => (),
                     BoardEvent::ZoneActionStopped => (),
+                    BoardEvent::ZoneActionRejected { .. } => (),
+                    BoardEvent::ZonesChanged { .. } => (),
+                    BoardEvent::RunStateChanged { .. } => (),
+                    BoardEvent::ProgramPaused { .. } => (),
+                    BoardEvent::ProgramResumed => (),
+                    BoardEvent::ProgressChanged { .. } => (),
+                    BoardEvent::SafetyCutoff { .. } => (),
+                    BoardEvent::RainDelayChanged { until: _ } => (),
+                    BoardEvent::RunMissed { .. } => (),
+                    BoardEvent::RunInterrupted { journal } => {
+                        info!("Interrupted program: {}", journal.program_id());
+                        let _ = schedule_tx.send(schedule::ScheduleCommand::RecoverRun(journal));
+                    }
+                    BoardEvent::RunRecoveryReady { journal, program } => {
+                        let _ = relay_tx.send(relay::RelayCommand::Recover { journal, program });
+                    }
+                    BoardEvent::RunRecovered { .. } => (),
+                    BoardEvent::FirmwareChunkRequested { version, offset } => {
+                        let _ = ws_tx.send(ws::WsCommand::FirmwareChunkRequest { version, offset });
+                    }
+                    BoardEvent::FirmwareUpdateFailed { .. } => (),
+                    BoardEvent::FirmwareRolledBack { .. } => (),
+                    BoardEvent::ServerConfigRejected { .. } => (),
+                    BoardEvent::RelayLayoutSaved { .. } => (),
+                    BoardEvent::RelayLayoutRejected { .. } => (),
+                    BoardEvent::RelayWriteFailed { .. } => (),
                 }
             }
             Err(e) => {
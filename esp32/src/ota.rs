@@ -0,0 +1,231 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use board_core::ota::{download, Chunk, ChunkTransport, DownloadError};
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender};
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+use esp_idf_svc::ota::{EspOta, SlotState};
+use log::info;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::BoardEvent;
+
+// A new firmware has to reconnect to the server within this time, otherwise it is rolled back
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+pub enum OtaCommand {
+    // Download and install a firmware release
+    Update {
+        version: String,
+        size: u64,
+        sha256: String,
+    },
+    // Part of the image being downloaded, base64 encoded
+    Chunk {
+        version: String,
+        offset: u64,
+        data: String,
+    },
+    // The board reconnected to the server
+    Confirm,
+}
+
+pub struct OtaModule {
+    rx: Receiver<OtaCommand>,
+    tx: Sender<BoardEvent>,
+    ota: EspOta,
+    nvs: EspNvs<NvsDefault>,
+    // Set while the running firmware waits to be confirmed
+    verify_deadline: Option<Instant>,
+}
+
+impl OtaModule {
+    pub fn new(
+        tx: Sender<BoardEvent>,
+        esp_partition: EspNvsPartition<NvsDefault>,
+    ) -> anyhow::Result<(Self, Sender<OtaCommand>)> {
+        let (module_tx, rx) = channel::unbounded::<OtaCommand>();
+
+        let nvs = EspNvs::new(esp_partition, "ota", true)?;
+        let ota = EspOta::new()?;
+
+        let mut res = Self {
+            rx,
+            tx,
+            ota,
+            nvs,
+            verify_deadline: None,
+        };
+
+        // Version installed before the last reboot, kept until it is confirmed
+        let mut buf = [0u8; 64];
+        let pending = res
+            .nvs
+            .get_str("ota_pending", &mut buf)?
+            .map(str::to_string);
+        if let Some(version) = pending {
+            if res.ota.get_running_slot()?.state == SlotState::Unverified {
+                info!("Firmware {} waits for the server to confirm it", version);
+                res.verify_deadline = Some(Instant::now() + VERIFY_TIMEOUT);
+            } else {
+                // The bootloader went back to the previous image
+                info!("Firmware {} was rolled back", version);
+                res.nvs.remove("ota_pending")?;
+                let _ = res.tx.send(BoardEvent::FirmwareRolledBack { version });
+            }
+        }
+
+        Ok((res, module_tx))
+    }
+
+    pub fn start(self) {
+        thread::Builder::new()
+            .name("ota_module".into())
+            .stack_size(16384)
+            .spawn(move || {
+                self.run();
+            })
+            .expect("Failed to spawn OTA thread");
+    }
+
+    fn run(mut self) {
+        loop {
+            let timeout = self
+                .verify_deadline
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+                .unwrap_or(Duration::from_secs(3600));
+
+            match self.rx.recv_timeout(timeout) {
+                Ok(OtaCommand::Update {
+                    version,
+                    size,
+                    sha256,
+                }) => {
+                    if self.verify_deadline.is_some() {
+                        info!("Firmware not confirmed yet, ignoring update to {}", version);
+                        continue;
+                    }
+                    // Restarts the board on success
+                    if let Err(e) = self.update(&version, size, &sha256) {
+                        info!("Firmware update to {} failed: {}", version, e);
+                        let _ = self.tx.send(BoardEvent::FirmwareUpdateFailed {
+                            version,
+                            error: e.to_string(),
+                        });
+                    }
+                }
+                Ok(OtaCommand::Chunk {
+                    version, offset, ..
+                }) => {
+                    info!("Unexpected chunk {} of firmware {}", offset, version);
+                }
+                Ok(OtaCommand::Confirm) => {
+                    if self.verify_deadline.take().is_some() {
+                        self.confirm();
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if self
+                        .verify_deadline
+                        .is_some_and(|deadline| Instant::now() >= deadline)
+                    {
+                        info!("Firmware was not confirmed in time, rolling back");
+                        let e = self.ota.mark_running_slot_invalid_and_reboot();
+                        info!("Failed to roll back firmware: {}", e);
+                        self.verify_deadline = None;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    info!("OtaModule command channel closed.");
+                    break;
+                }
+            }
+        }
+    }
+
+    // The new firmware reached the server, keep it
+    fn confirm(&mut self) {
+        match self.ota.mark_running_slot_valid() {
+            Ok(()) => {
+                info!("Firmware confirmed");
+                if let Err(e) = self.nvs.remove("ota_pending") {
+                    info!("Failed to clear pending firmware from NVS: {}", e);
+                }
+            }
+            Err(e) => info!("Failed to mark firmware valid: {}", e),
+        }
+    }
+
+    fn update(&mut self, version: &str, size: u64, sha256: &str) -> anyhow::Result<()> {
+        info!("Updating firmware to {} ({} bytes)", version, size);
+        let mut update = self.ota.initiate_update()?;
+        let mut transport = ServerTransport {
+            rx: &self.rx,
+            tx: &self.tx,
+        };
+        let downloaded = download(&mut transport, version, size, sha256, |data| {
+            update
+                .write(data)
+                .map_err(|e| DownloadError::Write(e.to_string()))
+        });
+        if let Err(e) = downloaded {
+            let _ = update.abort();
+            return Err(e.into());
+        }
+        update.complete()?;
+
+        // Checked after the reboot, the image is confirmed or rolled back
+        self.nvs.set_str("ota_pending", version)?;
+        info!("Firmware {} installed, restarting", version);
+        esp_idf_svc::hal::reset::restart();
+    }
+}
+
+// Chunk requests go out as board events, the answers arrive as OTA commands
+struct ServerTransport<'a> {
+    rx: &'a Receiver<OtaCommand>,
+    tx: &'a Sender<BoardEvent>,
+}
+
+impl ChunkTransport for ServerTransport<'_> {
+    fn request(&mut self, version: &str, offset: u64) {
+        let _ = self.tx.send(BoardEvent::FirmwareChunkRequested {
+            version: version.to_string(),
+            offset,
+        });
+    }
+
+    fn receive(&mut self, deadline: Instant) -> Result<Option<Chunk>, DownloadError> {
+        loop {
+            match self
+                .rx
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                Ok(OtaCommand::Chunk {
+                    version,
+                    offset,
+                    data,
+                }) => {
+                    let data = BASE64
+                        .decode(data)
+                        .map_err(|e| DownloadError::Transport(e.to_string()))?;
+                    return Ok(Some(Chunk {
+                        version,
+                        offset,
+                        data,
+                    }));
+                }
+                Ok(OtaCommand::Update { version, .. }) => {
+                    info!("Already updating, ignoring update to {}", version);
+                }
+                Ok(OtaCommand::Confirm) => (),
+                Err(RecvTimeoutError::Timeout) => return Ok(None),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(DownloadError::Transport(
+                        "OtaModule command channel closed".to_string(),
+                    ))
+                }
+            }
+        }
+    }
+}
@@ -1,27 +1,31 @@
 use std::{
+    collections::HashMap,
     thread,
     time::{Duration, Instant},
 };
 
-use crate::{BoardEvent, Program, ZoneAction};
+use crate::{BoardEvent, I2cBus, Program, ProgramProgress, RunState, ZoneAction};
+use board_core::expander::{Expander, Mcp23017, Pcf8574, RelayPin};
+use board_core::plan::{build_run_plan, RunStep};
+use chrono::{DateTime, Utc};
 use crossbeam::{
     channel::{Receiver, Sender},
     select,
 };
 use esp_idf_svc::hal::gpio::{AnyIOPin, Output, Pin, PinDriver};
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
 use log::info;
+use serde::{Deserialize, Serialize};
 
-pub trait RelayPin: Send {
-    fn set_high(&mut self);
-    fn set_low(&mut self);
-}
+// ESP32 GPIO driving a relay
+pub struct GpioPin<P: Pin>(PinDriver<'static, P, Output>);
 
-impl<P: Pin + Into<AnyIOPin>> RelayPin for PinDriver<'static, P, Output> {
+impl<P: Pin + Into<AnyIOPin>> RelayPin for GpioPin<P> {
     fn set_high(&mut self) {
         let mut retries = 0;
         let mut res = false;
         while !res && retries < 5 {
-            res = self.set_high().is_ok();
+            res = self.0.set_high().is_ok();
             if !res {
                 info!("Failed to set pin high, retrying...");
                 thread::sleep(Duration::from_millis(100));
@@ -34,7 +38,7 @@ impl<P: Pin + Into<AnyIOPin>> RelayPin for PinDriver<'static, P, Output> {
         let mut retries = 0;
         let mut res = false;
         while !res && retries < 5 {
-            res = self.set_low().is_ok();
+            res = self.0.set_low().is_ok();
             if !res {
                 info!("Failed to set pin low, retrying...");
                 thread::sleep(Duration::from_millis(100));
@@ -47,84 +51,719 @@ impl<P: Pin + Into<AnyIOPin>> RelayPin for PinDriver<'static, P, Output> {
 pub struct Relay {
     id: String,
     pin: Box<dyn RelayPin>,
+    // The relay is energized by a low output
+    active_low: bool,
+    // Set while the relay is open
+    opened_at: Option<Instant>,
 }
 
 impl Relay {
-    pub fn new<T: RelayPin + 'static>(id: String, pin: T) -> Self {
+    pub fn new<T: RelayPin + 'static>(id: String, pin: T, active_low: bool) -> Self {
         Relay {
             id,
             pin: Box::new(pin),
+            active_low,
+            opened_at: None,
         }
     }
 
     pub fn open(&mut self) {
-        self.pin.set_high();
+        if self.active_low {
+            self.pin.set_low();
+        } else {
+            self.pin.set_high();
+        }
+        if self.opened_at.is_none() {
+            self.opened_at = Some(Instant::now());
+        }
     }
 
     pub fn close(&mut self) {
-        self.pin.set_low();
+        if self.active_low {
+            self.pin.set_high();
+        } else {
+            self.pin.set_low();
+        }
+        self.opened_at = None;
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.pin.flush()
+    }
+}
+
+// Relay outputs could not be written, the relays may not be in the state they were set to
+#[derive(Debug)]
+pub struct RelayWriteError {
+    relay_ids: Vec<String>,
+    error: String,
+}
+
+impl std::fmt::Display for RelayWriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "relays {:?} not switched: {}",
+            self.relay_ids, self.error
+        )
+    }
+}
+
+impl std::error::Error for RelayWriteError {}
+
+// GPIOs a relay may be wired to
+// Flash (6-11), input only (34-39), UART0 (1, 3), the DS3231 I2C bus (21, 22)
+// and the strapping pins 0 and 12 are left out, so are 16 and 17 which drive the PSRAM of WROVER modules.
+pub const SAFE_GPIOS: [u8; 14] = [2, 4, 5, 13, 14, 15, 18, 19, 23, 25, 26, 27, 32, 33];
+
+// Relays of a board, GPIOs and expander pins together
+const MAX_RELAYS: usize = 32;
+
+const MAX_LABEL_LEN: usize = 32;
+
+// I2C GPIO expander on the DS3231 bus
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExpanderChip {
+    // PCF8574 or PCF8574A
+    Pcf8574,
+    Mcp23017,
+}
+
+impl ExpanderChip {
+    fn pins(self) -> u8 {
+        match self {
+            ExpanderChip::Pcf8574 => 8,
+            ExpanderChip::Mcp23017 => 16,
+        }
+    }
+
+    fn valid_address(self, address: u8) -> bool {
+        match self {
+            ExpanderChip::Pcf8574 => matches!(address, 0x20..=0x27 | 0x38..=0x3f),
+            ExpanderChip::Mcp23017 => matches!(address, 0x20..=0x27),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ExpanderAddress {
+    pub chip: ExpanderChip,
+    pub address: u8,
+}
+
+// Wiring of one relay
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RelayPinConfig {
+    // ESP32 GPIO, or the expander pin if the relay is on an expander
+    pub gpio: u8,
+    #[serde(default)]
+    pub expander: Option<ExpanderAddress>,
+    #[serde(default)]
+    pub active_low: bool,
+    #[serde(default)]
+    pub label: Option<String>,
+}
+
+// Relays of the board, ids are numbered from 1 in this order
+// Stored in NVS and applied at boot.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RelayLayout {
+    pub relays: Vec<RelayPinConfig>,
+}
+
+impl Default for RelayLayout {
+    // Seven active-high relays of the first boards
+    fn default() -> Self {
+        Self {
+            relays: [2, 4, 5, 25, 26, 18, 19]
+                .into_iter()
+                .map(|gpio| RelayPinConfig {
+                    gpio,
+                    expander: None,
+                    active_low: false,
+                    label: None,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl RelayLayout {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.relays.is_empty() || self.relays.len() > MAX_RELAYS {
+            anyhow::bail!("A layout has 1 to {} relays", MAX_RELAYS);
+        }
+        for (i, relay) in self.relays.iter().enumerate() {
+            match relay.expander {
+                None => {
+                    if !SAFE_GPIOS.contains(&relay.gpio) {
+                        anyhow::bail!("GPIO {} is not safe for a relay", relay.gpio);
+                    }
+                }
+                Some(expander) => {
+                    if !expander.chip.valid_address(expander.address) {
+                        anyhow::bail!(
+                            "{:?} can not be at address {:#04x}",
+                            expander.chip,
+                            expander.address
+                        );
+                    }
+                    if relay.gpio >= expander.chip.pins() {
+                        anyhow::bail!("{:?} has no pin {}", expander.chip, relay.gpio);
+                    }
+                    // One address is one chip
+                    if self.relays.iter().any(|r| {
+                        r.expander.is_some_and(|e| {
+                            e.address == expander.address && e.chip != expander.chip
+                        })
+                    }) {
+                        anyhow::bail!("Address {:#04x} is used by two chips", expander.address);
+                    }
+                }
+            }
+            if self.relays[..i]
+                .iter()
+                .any(|r| r.gpio == relay.gpio && r.expander == relay.expander)
+            {
+                anyhow::bail!("Pin {} is used twice", relay.gpio);
+            }
+            if relay
+                .label
+                .as_ref()
+                .is_some_and(|l| l.len() > MAX_LABEL_LEN)
+            {
+                anyhow::bail!("Label of pin {} is too long", relay.gpio);
+            }
+        }
+        Ok(())
+    }
+
+    // Output pins of an expander and their levels with the relays closed
+    fn expander_levels(&self, expander: ExpanderAddress) -> (u16, u16) {
+        let mut outputs = 0;
+        let mut levels = 0;
+        for relay in &self.relays {
+            if relay.expander == Some(expander) {
+                outputs |= 1 << relay.gpio;
+                if relay.active_low {
+                    levels |= 1 << relay.gpio;
+                }
+            }
+        }
+        (outputs, levels)
+    }
+}
+
+// Relay layout stored in NVS, the default layout if there is none or it is invalid
+pub fn load_layout(esp_partition: EspNvsPartition<NvsDefault>) -> RelayLayout {
+    let stored = EspNvs::new(esp_partition, "relay", true)
+        .map_err(anyhow::Error::from)
+        .and_then(|nvs| load_relay_layout_from_nvs(&nvs));
+    match stored {
+        Ok(Some(layout)) => match layout.validate() {
+            Ok(()) => layout,
+            Err(e) => {
+                info!("Stored relay layout is invalid: {}", e);
+                RelayLayout::default()
+            }
+        },
+        Ok(None) => RelayLayout::default(),
+        Err(e) => {
+            info!("Failed to load relay layout from NVS: {}", e);
+            RelayLayout::default()
+        }
+    }
+}
+
+// Relays of a validated layout, closed
+// Expander relays are driven through the I2C bus shared with the DS3231.
+pub fn build_relays(mac: &str, layout: &RelayLayout, i2c: &I2cBus) -> anyhow::Result<Vec<Relay>> {
+    let mut pcf8574 = HashMap::new();
+    let mut mcp23017 = HashMap::new();
+    for expander in layout.relays.iter().filter_map(|r| r.expander) {
+        let (outputs, levels) = layout.expander_levels(expander);
+        let address = expander.address;
+        let error = |e| anyhow::anyhow!("{:?} at {:#04x}: {:?}", expander.chip, address, e);
+        match expander.chip {
+            ExpanderChip::Pcf8574 if !pcf8574.contains_key(&address) => {
+                let port = Pcf8574::new(i2c.clone(), address);
+                pcf8574.insert(
+                    address,
+                    Expander::new(port, outputs, levels).map_err(error)?,
+                );
+            }
+            ExpanderChip::Mcp23017 if !mcp23017.contains_key(&address) => {
+                let port = Mcp23017::new(i2c.clone(), address);
+                mcp23017.insert(
+                    address,
+                    Expander::new(port, outputs, levels).map_err(error)?,
+                );
+            }
+            _ => (),
+        }
+    }
+
+    let mut relays = Vec::with_capacity(layout.relays.len());
+    for (i, config) in layout.relays.iter().enumerate() {
+        let id = format!("{mac}/{}", i + 1);
+        let mut relay = match config.expander {
+            Some(ExpanderAddress {
+                chip: ExpanderChip::Pcf8574,
+                address,
+            }) => Relay::new(id, pcf8574[&address].pin(config.gpio), config.active_low),
+            Some(ExpanderAddress {
+                chip: ExpanderChip::Mcp23017,
+                address,
+            }) => Relay::new(id, mcp23017[&address].pin(config.gpio), config.active_low),
+            None => {
+                let gpio = config.gpio as i32;
+                // Latch the closed level before the pin becomes an output, active-low relays would click otherwise
+                if config.active_low {
+                    unsafe {
+                        esp_idf_svc::sys::gpio_set_level(gpio, 1);
+                    }
+                }
+                // Safe GPIOs are not used by any other driver
+                let pin = unsafe { AnyIOPin::new(gpio) };
+                Relay::new(id, GpioPin(PinDriver::output(pin)?), config.active_low)
+            }
+        };
+        relay.close();
+        relay.flush().map_err(anyhow::Error::msg)?;
+        relays.push(relay);
+    }
+    Ok(relays)
+}
+
+// Program id of ad-hoc zone actions
+const AD_HOC_PROGRAM_ID: &str = "single";
+
+// Failsafe maximum runtime of a zone relay if not configured otherwise
+const DEFAULT_MAX_RUNTIME_SECONDS: u32 = 2 * 60 * 60;
+
+fn default_max_runtime_seconds() -> u32 {
+    DEFAULT_MAX_RUNTIME_SECONDS
+}
+
+// An interrupted run is not continued after the board was down this long
+const DEFAULT_RECOVERY_MAX_AGE_MINUTES: u32 = 60;
+
+fn default_recovery_max_age_minutes() -> u32 {
+    DEFAULT_RECOVERY_MAX_AGE_MINUTES
+}
+
+// The run journal of a running step is rewritten this often,
+// a reboot loses at most this much of the watered time
+const JOURNAL_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+// Board level relay configuration
+// Stored in NVS as JSON, so new fields can be added with defaults.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RelayConfig {
+    // Maximum number of simultaneously open relays, None means no limit
+    #[serde(default)]
+    max_open: Option<u32>,
+    // Pairs of relay ids which must never be open together
+    #[serde(default)]
+    forbidden_pairs: Vec<(String, String)>,
+    // Pump or master valve relay, it is not a zone
+    #[serde(default)]
+    master: Option<MasterValve>,
+    // Delay between sequential zones, programs can override it
+    #[serde(default)]
+    inter_zone_delay_seconds: u32,
+    // Safety cutoff, a zone relay is force closed after being open this long
+    #[serde(default = "default_max_runtime_seconds")]
+    max_runtime_seconds: u32,
+    // Safety cutoff of individual relays, overrides max_runtime_seconds
+    #[serde(default)]
+    relay_max_runtime_seconds: HashMap<String, u32>,
+    // What to do with a program interrupted by a reboot
+    #[serde(default)]
+    recovery_policy: RecoveryPolicy,
+    // A run journal older than this is skipped instead of recovered
+    #[serde(default = "default_recovery_max_age_minutes")]
+    recovery_max_age_minutes: u32,
+}
+
+// Handling of a program interrupted by a reboot
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub enum RecoveryPolicy {
+    // Continue the interrupted step with the time it had left
+    #[default]
+    Resume,
+    // Drop the interrupted program
+    Skip,
+    // Run the interrupted program again from its first zone
+    Restart,
+}
+
+// What happened to a program interrupted by a reboot
+#[derive(Serialize, Debug, Clone, Copy)]
+pub enum RecoveryOutcome {
+    Resumed,
+    Skipped,
+    Restarted,
+    // The program is no longer in the schedule
+    ProgramNotFound,
+    // Another program was started before the recovery
+    Superseded,
+}
+
+// Running program state persisted in NVS, so a reboot can be recovered
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunJournal {
+    program_id: String,
+    // Step of the run plan
+    zone_index: usize,
+    // Seconds left of the step, the time already watered is taken off
+    remaining_seconds: u64,
+    // Unix timestamp of the last write, the board went down after it
+    written_at: i64,
+    water_budget: u16,
+}
+
+impl RunJournal {
+    pub fn program_id(&self) -> &str {
+        &self.program_id
+    }
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            max_open: None,
+            forbidden_pairs: vec![],
+            master: None,
+            inter_zone_delay_seconds: 0,
+            max_runtime_seconds: DEFAULT_MAX_RUNTIME_SECONDS,
+            relay_max_runtime_seconds: HashMap::new(),
+            recovery_policy: RecoveryPolicy::default(),
+            recovery_max_age_minutes: DEFAULT_RECOVERY_MAX_AGE_MINUTES,
+        }
     }
 }
 
+// Master valve or pump relay
+// Switched on lead_seconds before the first zone opens
+// and switched off lag_seconds after the last zone closes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MasterValve {
+    relay_id: String,
+    lead_seconds: u32,
+    lag_seconds: u32,
+}
+
 pub struct RelayController {
     relays: Vec<Relay>,
+    config: RelayConfig,
+    master_on: bool,
 }
 
 impl RelayController {
     pub fn new(relays: Vec<Relay>) -> Self {
-        Self { relays }
+        Self {
+            relays,
+            config: RelayConfig::default(),
+            master_on: false,
+        }
+    }
+
+    pub fn set_config(&mut self, config: RelayConfig) -> Result<(), RelayWriteError> {
+        // The master valve may have been moved to another relay
+        let res = self.master_off();
+        self.config = config;
+        res
+    }
+
+    // Switch the relays changed since the last flush, expander relays switch together
+    // Every relay is flushed even if an earlier one failed.
+    fn flush(&mut self) -> Result<(), RelayWriteError> {
+        let mut failed: Option<RelayWriteError> = None;
+        for relay in &mut self.relays {
+            if let Err(error) = relay.flush() {
+                match &mut failed {
+                    Some(failed) => failed.relay_ids.push(relay.id.clone()),
+                    None => {
+                        failed = Some(RelayWriteError {
+                            relay_ids: vec![relay.id.clone()],
+                            error,
+                        })
+                    }
+                }
+            }
+        }
+        failed.map_or(Ok(()), Err)
+    }
+
+    fn is_master(&self, id: &str) -> bool {
+        self.config
+            .master
+            .as_ref()
+            .is_some_and(|m| m.relay_id == id)
     }
 
-    pub fn close_all(&mut self) {
+    // Close every relay, the master valve included
+    pub fn close_all(&mut self) -> Result<(), RelayWriteError> {
         for relay in &mut self.relays {
             relay.close();
         }
+        self.master_on = false;
+        self.flush()?;
         info!("All relays closed");
+        Ok(())
+    }
+
+    // Close every zone relay, the master valve is left as it is
+    fn close_zones(&mut self) -> Result<(), RelayWriteError> {
+        let master = self.config.master.as_ref().map(|m| m.relay_id.clone());
+        for relay in &mut self.relays {
+            if master.as_ref() != Some(&relay.id) {
+                relay.close();
+            }
+        }
+        self.flush()?;
+        info!("All zone relays closed");
+        Ok(())
     }
 
-    fn open(&mut self, ids: Vec<String>) {
-        self.close_all();
+    // Open the given relays, close every other zone relay
+    // Nothing is opened if the relays would violate the board constraints.
+    // If the relays could not be switched they are closed again.
+    fn open(&mut self, ids: Vec<String>) -> anyhow::Result<()> {
+        self.close_zones()?;
+        self.check_constraints(&ids)?;
+        let master = self.config.master.as_ref().map(|m| m.relay_id.clone());
         for relay in &mut self.relays {
-            if ids.contains(&relay.id) {
+            if ids.contains(&relay.id) && master.as_ref() != Some(&relay.id) {
                 relay.open();
             }
         }
+        if let Err(e) = self.flush() {
+            let _ = self.close_zones();
+            return Err(e.into());
+        }
         info!("Relays opened: {:?}", ids);
+        Ok(())
+    }
+
+    fn check_constraints(&self, ids: &[String]) -> anyhow::Result<()> {
+        // Only the zone relays of this board count
+        let own: Vec<&String> = ids
+            .iter()
+            .filter(|id| !self.is_master(id) && self.relays.iter().any(|r| &r.id == *id))
+            .collect();
+
+        if let Some(max_open) = self.config.max_open {
+            if own.len() > max_open as usize {
+                anyhow::bail!(
+                    "{} relays would be open, the maximum is {}",
+                    own.len(),
+                    max_open
+                );
+            }
+        }
+        for (a, b) in &self.config.forbidden_pairs {
+            if own.contains(&a) && own.contains(&b) {
+                anyhow::bail!("{} and {} must not be open together", a, b);
+            }
+        }
+        Ok(())
+    }
+
+    // Switch the master valve on
+    // Returns the lead time the zones have to wait for,
+    // zero if it is already on or there is no master valve.
+    fn master_on(&mut self) -> Result<Duration, RelayWriteError> {
+        let Some(master) = self.config.master.clone() else {
+            return Ok(Duration::ZERO);
+        };
+        if self.master_on {
+            return Ok(Duration::ZERO);
+        }
+        if let Some(relay) = self.relays.iter_mut().find(|r| r.id == master.relay_id) {
+            relay.open();
+        }
+        self.master_on = true;
+        self.flush()?;
+        info!("Master valve {} on", master.relay_id);
+        Ok(Duration::from_secs(master.lead_seconds as u64))
+    }
+
+    fn master_off(&mut self) -> Result<(), RelayWriteError> {
+        if !self.master_on {
+            return Ok(());
+        }
+        self.master_on = false;
+        if let Some(master) = self.config.master.clone() {
+            if let Some(relay) = self.relays.iter_mut().find(|r| r.id == master.relay_id) {
+                relay.close();
+            }
+            self.flush()?;
+            info!("Master valve {} off", master.relay_id);
+        }
+        Ok(())
+    }
+
+    // Lag time of the master valve, None if it is off
+    fn master_lag(&self) -> Option<Duration> {
+        match &self.config.master {
+            Some(master) if self.master_on => Some(Duration::from_secs(master.lag_seconds as u64)),
+            _ => None,
+        }
+    }
+
+    // Force close every zone relay which is open longer than its maximum runtime
+    // Returns the closed relays with their open and maximum seconds,
+    // they are switched by the next flush.
+    fn enforce_max_runtime(&mut self) -> Vec<(String, u64, u32)> {
+        let mut closed = vec![];
+        let master = self.config.master.as_ref().map(|m| m.relay_id.clone());
+        for relay in &mut self.relays {
+            let Some(opened_at) = relay.opened_at else {
+                continue;
+            };
+            if master.as_ref() == Some(&relay.id) {
+                continue;
+            }
+            let max_seconds = self
+                .config
+                .relay_max_runtime_seconds
+                .get(&relay.id)
+                .copied()
+                .unwrap_or(self.config.max_runtime_seconds);
+            let open_seconds = opened_at.elapsed().as_secs();
+            if open_seconds >= max_seconds as u64 {
+                relay.close();
+                info!(
+                    "Safety cutoff: relay {} was open for {} seconds, closed",
+                    relay.id, open_seconds
+                );
+                closed.push((relay.id.clone(), open_seconds, max_seconds));
+            }
+        }
+        closed
     }
 
+    // Zone relay ids, the master valve is not a zone
     pub fn get_zones(&self) -> Vec<String> {
-        self.relays.iter().map(|r| r.id.clone()).collect()
+        self.relays
+            .iter()
+            .filter(|r| !self.is_master(&r.id))
+            .map(|r| r.id.clone())
+            .collect()
     }
 }
 
 pub enum RelayCommand {
-    StartProgram(Program),
+    // Program with its effective water budget in percent
+    StartProgram(Program, u16),
     StartZoneAction(ZoneAction),
     Stop,
+    SetConfig(RelayConfig),
+    // Pause the running program, resume automatically after the given seconds
+    Pause(Option<u32>),
+    Resume,
+    // Cut the current zone short
+    SkipZone,
+    // Add seconds to the current zone
+    ExtendZone(u32),
+    // Apply the recovery policy to the program found in the run journal
+    Recover {
+        journal: RunJournal,
+        program: Option<Program>,
+    },
+    // Store a new relay layout, used from the next boot
+    SetLayout(RelayLayout),
+}
+
+// Paused program step
+struct Pause {
+    // Remaining time of the current step
+    remaining: Duration,
+    // Resume automatically at this time
+    resume_at: Option<Instant>,
 }
 
 pub struct RelayModule {
     relay_controller: RelayController,
     tx: Sender<BoardEvent>,
     rx: Receiver<RelayCommand>,
+    nvs: EspNvs<NvsDefault>,
+    current_program: Option<Program>,
+    // Effective water budget of the current program
+    water_budget: u16,
+    // Steps of the current program, current_zone_index points into it
+    run_plan: Vec<RunStep>,
+    current_zone_index: Option<usize>,
+    zone_start_time: Option<Instant>,
+    // Zones of the current step wait for the master valve lead time
+    zones_open_at: Option<Instant>,
+    // Master valve is switched off after its lag time
+    master_off_at: Option<Instant>,
+    paused: Option<Pause>,
+    journal_saved_at: Option<Instant>,
 }
 
 impl RelayModule {
     pub fn new(
-        relay_controller: RelayController,
+        mut relay_controller: RelayController,
         tx: Sender<BoardEvent>,
+        esp_partition: EspNvsPartition<NvsDefault>,
     ) -> (Self, Sender<RelayCommand>) {
         let (module_tx, rx) = crossbeam::channel::unbounded::<RelayCommand>();
+
+        let nvs = EspNvs::new(esp_partition, "relay", true).expect("Failed to create NVS");
+
+        // Apply the stored relay config
+        match load_relay_config_from_nvs(&nvs) {
+            Ok(Some(config)) => {
+                info!("Relay config loaded from NVS: {:?}", config);
+                if let Err(e) = relay_controller.set_config(config) {
+                    info!("Failed to switch the master valve off: {}", e);
+                    let _ = tx.send(BoardEvent::RelayWriteFailed {
+                        error: e.to_string(),
+                    });
+                }
+            }
+            Ok(None) => info!("No relay config found in NVS."),
+            Err(e) => info!("Failed to load relay config from NVS: {}", e),
+        }
+
+        // A program was running when the board went down
+        match load_run_journal_from_nvs(&nvs) {
+            Ok(Some(journal)) => {
+                info!("Interrupted run found in NVS: {:?}", journal);
+                let _ = tx.send(BoardEvent::RunInterrupted { journal });
+            }
+            Ok(None) => (),
+            Err(e) => info!("Failed to load run journal from NVS: {}", e),
+        }
+
         (
             Self {
                 relay_controller,
                 tx,
                 rx,
+                nvs,
+                current_program: None,
+                water_budget: 100,
+                run_plan: vec![],
+                current_zone_index: None,
+                zone_start_time: None,
+                zones_open_at: None,
+                master_off_at: None,
+                paused: None,
+                journal_saved_at: None,
             },
             module_tx,
         )
     }
 
+    pub fn get_zones(&self) -> Vec<String> {
+        self.relay_controller.get_zones()
+    }
+
     pub fn start(self) {
         thread::Builder::new()
             .name("schedule_module".into())
@@ -136,10 +775,6 @@ impl RelayModule {
     }
 
     pub fn run(mut self) {
-        let mut current_zone_index: Option<usize> = None;
-        let mut current_program: Option<Program> = None;
-        let mut zone_start_time: Option<Instant> = None;
-
         loop {
             select! {
                 recv(self.rx) -> msg => {
@@ -147,83 +782,655 @@ impl RelayModule {
                         Ok(RelayCommand::Stop) => {
                             // Stop all relays and programs
                             info!("Stopping all relays and programs");
-                            self.relay_controller.close_all();
+                            self.close_zones();
+                            self.release_master();
+                            let _ = self.tx.send(BoardEvent::RunStateChanged { state: RunState::Idle });
                             // Notify program stopped
                             let _ = self.tx.send(BoardEvent::ProgramStopped);
                             // Notify zone action stopped
                             let _ = self.tx.send(BoardEvent::ZoneActionStopped);
                             // Reset state
-                            current_zone_index = None;
-                            current_program = None;
-                            zone_start_time = None;
+                            self.reset();
                         },
                         Ok(RelayCommand::StartZoneAction(zone)) => {
-                            self.relay_controller.open(zone.zone_ids.clone());
-                            // Send board action started event
-                            let _ = self.tx.send(BoardEvent::ZoneActionStarted { zone_action: zone.clone() });
-                            // Reset current program and zone index
-                            zone_start_time = Some(Instant::now());
-                            current_zone_index = Some(0);
-                            current_program = Some(Program {
-                                id: "single".into(),
+                            // Run the zone action as an ad-hoc program
+                            self.water_budget = 100;
+                            self.load_program(Program {
+                                id: AD_HOC_PROGRAM_ID.into(),
                                 name: "Ad-hoc".into(),
-                                weekdays: vec![],
                                 start_time: chrono::NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
                                 active: true,
                                 zones: vec![zone],
+                                ..Default::default()
                             });
+                            self.start_step(0);
                         },
-                        Ok(RelayCommand::StartProgram(prog)) => {
-                            // Notify program running
-                            let _ = self.tx.send(BoardEvent::ProgramRunning { program: prog.clone() });
-
-                            // Open the first zone of the program
-                            if let Some(first_zone) = prog.zones.get(0) {
-                                let _ = self.tx.send(BoardEvent::ZoneActionStarted { zone_action: first_zone.clone() });
-                                self.relay_controller.open(first_zone.zone_ids.clone());
+                        Ok(RelayCommand::StartProgram(prog, water_budget)) => {
+                            self.run_program(prog, water_budget);
+                            self.start_step(0);
+                        },
+                        Ok(RelayCommand::Recover { journal, program }) => {
+                            self.recover(journal, program);
+                        },
+                        Ok(RelayCommand::SetLayout(layout)) => {
+                            self.set_layout(layout);
+                        },
+                        Ok(RelayCommand::SetConfig(config)) => {
+                            info!("Relay config updated: {:?}", config);
+                            if let Err(e) = save_relay_config_to_nvs(&mut self.nvs, &config) {
+                                info!("Failed to save relay config to NVS: {}", e);
                             }
-
-                            current_program = Some(prog);
-                            current_zone_index = Some(0);
-                            zone_start_time = Some(Instant::now());
-
+                            if let Err(e) = self.relay_controller.set_config(config) {
+                                self.relay_write_failed(&e);
+                            }
+                            self.master_off_at = None;
+                            let _ = self.tx.send(BoardEvent::ZonesChanged { zones: self.relay_controller.get_zones() });
+                        },
+                        Ok(RelayCommand::Pause(auto_resume_seconds)) => {
+                            self.pause(auto_resume_seconds);
+                        },
+                        Ok(RelayCommand::Resume) => {
+                            self.resume();
+                        },
+                        Ok(RelayCommand::SkipZone) => {
+                            self.skip_zone();
+                        },
+                        Ok(RelayCommand::ExtendZone(seconds)) => {
+                            self.extend_zone(seconds);
                         },
                         Err(_) => break, // channel closed
                     }
                 },
                 default(Duration::from_millis(200)) => {
-                    if let (Some(ref prog), Some(index), Some(start)) =
-                        (&current_program, current_zone_index, zone_start_time)
+                    // Resume automatically once the pause timeout has elapsed
+                    if self
+                        .paused
+                        .as_ref()
+                        .and_then(|p| p.resume_at)
+                        .is_some_and(|at| Instant::now() >= at)
                     {
-                        let elapsed = Instant::now().duration_since(start).as_secs();
-
-                        if let Some(zone) = prog.zones.get(index) {
-                            if elapsed >= zone.duration_seconds as u64 {
-
-                                // Close all relays
-                                self.relay_controller.close_all();
-
-                                let _ = self.tx.send(BoardEvent::ZoneActionStopped);
-
-                                let next_index = index + 1;
-                                if let Some(next_zone) = prog.zones.get(next_index) {
-                                    let _ = self.tx.send(BoardEvent::ZoneActionStarted {
-                                        zone_action: next_zone.clone(),
-                                    });
-                                    self.relay_controller.open(next_zone.zone_ids.clone());
-                                    current_zone_index = Some(next_index);
-                                    zone_start_time = Some(Instant::now());
-                                } else {
-                                    let _ = self.tx.send(BoardEvent::ProgramStopped);
-                                    current_program = None;
-                                    current_zone_index = None;
-                                    zone_start_time = None;
+                        info!("Pause timed out, resuming");
+                        self.resume();
+                    }
+
+                    // Open the zones once the master valve lead time has elapsed
+                    if self.zones_open_at.is_some_and(|at| Instant::now() >= at) {
+                        self.zones_open_at = None;
+                        if let Some(RunStep::Water { zone_action, .. }) =
+                            self.current_zone_index.and_then(|i| self.run_plan.get(i)).cloned()
+                        {
+                            self.open_zones(&zone_action);
+                        }
+                    }
+
+                    if let (Some(index), Some(start)) = (self.current_zone_index, self.zone_start_time) {
+                        if let Some(step) = self.run_plan.get(index) {
+                            if start.elapsed().as_secs() >= step.duration_seconds() {
+                                if let RunStep::Water { .. } = step {
+                                    // Close all zone relays
+                                    self.close_zones();
+
+                                    let _ = self.tx.send(BoardEvent::ZoneActionStopped);
+                                }
+
+                                self.start_step(index + 1);
+                            }
+                        }
+                    }
+
+                    // Safety cutoff, independent of the running program and commands
+                    let cut = self.relay_controller.enforce_max_runtime();
+                    if !cut.is_empty() {
+                        self.flush_relays();
+                        for (relay_id, open_seconds, max_seconds) in &cut {
+                            let _ = self.tx.send(BoardEvent::SafetyCutoff {
+                                relay_id: relay_id.clone(),
+                                open_seconds: *open_seconds,
+                                max_seconds: *max_seconds,
+                            });
+                        }
+                        // Move on if a relay of the current step was cut
+                        if let Some(index) = self.current_zone_index {
+                            if let Some(RunStep::Water { zone_action, .. }) = self.run_plan.get(index) {
+                                if cut.iter().any(|(id, _, _)| zone_action.zone_ids.contains(id)) {
+                                    self.close_zones();
+                                    let _ = self.tx.send(BoardEvent::ZoneActionStopped);
+                                    self.start_step(index + 1);
                                 }
                             }
                         }
                     }
+
+                    // Keep the remaining time of the running step up to date
+                    if self.zone_start_time.is_some()
+                        && self
+                            .journal_saved_at
+                            .map_or(true, |at| at.elapsed() >= JOURNAL_SAVE_INTERVAL)
+                    {
+                        self.write_journal();
+                    }
+
+                    // Switch the master valve off once its lag time has elapsed
+                    if self.master_off_at.is_some_and(|at| Instant::now() >= at) {
+                        self.master_off_at = None;
+                        if let Err(e) = self.relay_controller.master_off() {
+                            self.relay_write_failed(&e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Scale the program by its water budget, report it running and load it
+    fn run_program(&mut self, program: Program, water_budget: u16) {
+        // Scale the zone durations, the base program stays untouched
+        let program = apply_water_budget(program, water_budget);
+
+        // Notify program running
+        let _ = self.tx.send(BoardEvent::ProgramRunning {
+            program: program.clone(),
+            water_budget,
+        });
+
+        self.water_budget = water_budget;
+        self.load_program(program);
+    }
+
+    // Build the run plan of the program
+    fn load_program(&mut self, program: Program) {
+        let inter_zone_delay = program
+            .inter_zone_delay_seconds
+            .unwrap_or(self.relay_controller.config.inter_zone_delay_seconds);
+        self.run_plan = build_run_plan(&program.zones, inter_zone_delay);
+        self.current_program = Some(program);
+        // A pause belongs to the program it interrupted
+        self.paused = None;
+    }
+
+    // Continue a program interrupted by a reboot according to the recovery policy
+    // A program started since the boot, e.g. a caught up run, is not replaced;
+    // its journal has already overwritten the interrupted one.
+    fn recover(&mut self, journal: RunJournal, program: Option<Program>) {
+        let config = &self.relay_controller.config;
+        let policy = config.recovery_policy;
+        let age_minutes = (Utc::now().timestamp() - journal.written_at).max(0) / 60;
+        let stale = age_minutes > config.recovery_max_age_minutes as i64;
+        let outcome = match (program, policy) {
+            _ if self.current_program.is_some() => RecoveryOutcome::Superseded,
+            (None, _) => RecoveryOutcome::ProgramNotFound,
+            (Some(_), RecoveryPolicy::Skip) => RecoveryOutcome::Skipped,
+            (Some(_), _) if stale => {
+                info!("Run journal is {} minutes old, skipped", age_minutes);
+                RecoveryOutcome::Skipped
+            }
+            (Some(program), RecoveryPolicy::Restart) => {
+                self.run_program(program, journal.water_budget);
+                self.start_step(0);
+                RecoveryOutcome::Restarted
+            }
+            (Some(program), RecoveryPolicy::Resume) => {
+                self.run_program(program, journal.water_budget);
+                self.enter_step(
+                    journal.zone_index,
+                    Duration::from_secs(journal.remaining_seconds),
+                );
+                RecoveryOutcome::Resumed
+            }
+        };
+        info!(
+            "Interrupted program {} at step {}: {:?}",
+            journal.program_id, journal.zone_index, outcome
+        );
+        if self.current_program.is_none() {
+            self.clear_journal();
+        }
+        let _ = self.tx.send(BoardEvent::RunRecovered {
+            program_id: journal.program_id,
+            zone_index: journal.zone_index,
+            policy,
+            outcome,
+        });
+    }
+
+    // Persist the current step of the running program with its remaining time
+    // Ad-hoc zone actions are not recovered after a reboot.
+    fn write_journal(&mut self) {
+        let (Some(program), Some(index)) = (&self.current_program, self.current_zone_index) else {
+            return;
+        };
+        if program.id == AD_HOC_PROGRAM_ID {
+            return;
+        }
+        let now = Instant::now();
+        let remaining = match (&self.paused, self.zone_start_time) {
+            (Some(pause), _) => pause.remaining,
+            // An extended step starts in the future, the master valve lead is not watering
+            (None, Some(start)) => (start + self.step_duration(index))
+                .saturating_duration_since(self.zones_open_at.map_or(now, |at| at.max(now))),
+            (None, None) => return,
+        };
+        let journal = RunJournal {
+            program_id: program.id.clone(),
+            zone_index: index,
+            remaining_seconds: remaining.as_secs(),
+            written_at: Utc::now().timestamp(),
+            water_budget: self.water_budget,
+        };
+        if let Err(e) = save_run_journal_to_nvs(&mut self.nvs, &journal) {
+            info!("Failed to save run journal to NVS: {}", e);
+        }
+        self.journal_saved_at = Some(now);
+    }
+
+    // The pins are taken at boot, a new layout is only stored here
+    fn set_layout(&mut self, layout: RelayLayout) {
+        if let Err(e) = layout.validate() {
+            info!("Relay layout rejected: {}", e);
+            let _ = self.tx.send(BoardEvent::RelayLayoutRejected {
+                error: e.to_string(),
+            });
+            return;
+        }
+        if let Ok(Some(stored)) = load_relay_layout_from_nvs(&self.nvs) {
+            if stored == layout {
+                return;
+            }
+        }
+        if let Err(e) = save_relay_layout_to_nvs(&mut self.nvs, &layout) {
+            info!("Failed to save relay layout to NVS: {}", e);
+            return;
+        }
+        info!("Relay layout saved, applied at the next boot: {:?}", layout);
+        let _ = self.tx.send(BoardEvent::RelayLayoutSaved {
+            relays: layout.relays.len(),
+        });
+    }
+
+    fn clear_journal(&mut self) {
+        if let Err(e) = self.nvs.remove("run_journal") {
+            info!("Failed to remove run journal from NVS: {}", e);
+        }
+    }
+
+    // Planned duration of the step at index
+    fn step_duration(&self, index: usize) -> Duration {
+        self.run_plan
+            .get(index)
+            .map(|s| Duration::from_secs(s.duration_seconds()))
+            .unwrap_or_default()
+    }
+
+    // Start the step of the run plan at index
+    // The program is finished if there is no such step.
+    fn start_step(&mut self, index: usize) {
+        let duration = self.step_duration(index);
+        self.enter_step(index, duration);
+    }
+
+    // Enter the step of the run plan at index with remaining time left of it
+    // The remaining time may be longer than the step if it was extended.
+    fn enter_step(&mut self, index: usize, remaining: Duration) {
+        let now = Instant::now();
+        let duration = self.step_duration(index);
+        match self.run_plan.get(index).cloned() {
+            Some(RunStep::Water {
+                zone_action,
+                cycle,
+                cycles,
+                ..
+            }) => {
+                info!(
+                    "Cycle {}/{} of zones {:?} for {} seconds",
+                    cycle, cycles, zone_action.zone_ids, zone_action.duration_seconds
+                );
+                if let Err(e) = self
+                    .relay_controller
+                    .check_constraints(&zone_action.zone_ids)
+                {
+                    // Skip the zone action, it must not be opened
+                    info!("Zone action rejected: {}", e);
+                    let _ = self.tx.send(BoardEvent::ZoneActionRejected {
+                        zone_action,
+                        reason: e.to_string(),
+                    });
+                    self.start_step(index + 1);
+                    return;
+                }
+
+                // The master valve is switched on first,
+                // the zones are opened after its lead time
+                self.master_off_at = None;
+                let lead = match self.relay_controller.master_on() {
+                    Ok(lead) => lead,
+                    Err(e) => {
+                        self.relay_write_failed(&e);
+                        Duration::ZERO
+                    }
+                };
+                self.current_zone_index = Some(index);
+                self.zone_start_time = Some(step_start(now + lead, duration, remaining));
+                self.report_progress();
+                self.write_journal();
+                if lead.is_zero() {
+                    self.open_zones(&zone_action);
+                } else {
+                    info!("Opening zones after {} seconds lead", lead.as_secs());
+                    self.zones_open_at = Some(now + lead);
+                    let _ = self.tx.send(BoardEvent::RunStateChanged {
+                        state: RunState::Waiting,
+                    });
+                }
+                return;
+            }
+            Some(RunStep::Soak { seconds }) => {
+                info!("Soaking for {} seconds", seconds);
+                self.release_master();
+                let _ = self.tx.send(BoardEvent::RunStateChanged {
+                    state: RunState::Soaking,
+                });
+            }
+            Some(RunStep::Delay { seconds }) => {
+                info!("Waiting {} seconds before the next zone", seconds);
+                let _ = self.tx.send(BoardEvent::RunStateChanged {
+                    state: RunState::Waiting,
+                });
+            }
+            None => {
+                let _ = self.tx.send(BoardEvent::ProgramStopped);
+                let _ = self.tx.send(BoardEvent::RunStateChanged {
+                    state: RunState::Idle,
+                });
+                self.release_master();
+                self.reset();
+                return;
+            }
+        }
+        self.current_zone_index = Some(index);
+        self.zone_start_time = Some(step_start(now, duration, remaining));
+        self.report_progress();
+        self.write_journal();
+    }
+
+    // Pause the running program
+    // The relays are closed and the remaining time of the current step is kept.
+    fn pause(&mut self, auto_resume_seconds: Option<u32>) {
+        let (Some(index), Some(start)) = (self.current_zone_index, self.zone_start_time) else {
+            info!("No running program to pause");
+            return;
+        };
+        let Some(step) = self.run_plan.get(index) else {
+            return;
+        };
+
+        // An extended step starts in the future, the master valve lead not elapsed yet
+        // is not watering and is waited for again on resume
+        let now = Instant::now();
+        let remaining = (start + Duration::from_secs(step.duration_seconds()))
+            .saturating_duration_since(self.zones_open_at.map_or(now, |at| at.max(now)));
+        if let RunStep::Water { .. } = step {
+            self.close_zones();
+            let _ = self.tx.send(BoardEvent::ZoneActionStopped);
+        }
+        self.release_master();
+        self.zones_open_at = None;
+        // Without a start time the step does not progress
+        self.zone_start_time = None;
+
+        let auto_resume = auto_resume_seconds.map(|s| Duration::from_secs(s as u64));
+        self.paused = Some(Pause {
+            remaining,
+            resume_at: auto_resume.map(|d| Instant::now() + d),
+        });
+        info!(
+            "Program paused, {} seconds remaining of the current step",
+            remaining.as_secs()
+        );
+        self.report_progress();
+        self.write_journal();
+
+        let _ = self.tx.send(BoardEvent::ProgramPaused {
+            resume_at: auto_resume_seconds
+                .map(|s| Utc::now() + chrono::Duration::seconds(s as i64)),
+        });
+        let _ = self.tx.send(BoardEvent::RunStateChanged {
+            state: RunState::Paused,
+        });
+    }
+
+    // Close the zones of the current step and continue with the next one
+    fn skip_zone(&mut self) {
+        if self.paused.is_some() {
+            info!("Program is paused, resume it before skipping a zone");
+            return;
+        }
+        let Some(index) = self.current_zone_index else {
+            info!("No running zone to skip");
+            return;
+        };
+        info!("Skipping step {}", index);
+        if let Some(RunStep::Water { .. }) = self.run_plan.get(index) {
+            self.close_zones();
+            self.zones_open_at = None;
+            let _ = self.tx.send(BoardEvent::ZoneActionStopped);
+        }
+        self.start_step(index + 1);
+    }
+
+    // Give the current step more time
+    fn extend_zone(&mut self, seconds: u32) {
+        let extra = Duration::from_secs(seconds as u64);
+        if let Some(pause) = &mut self.paused {
+            pause.remaining += extra;
+            info!("Paused step extended by {} seconds", seconds);
+            return;
+        }
+        let Some(start) = self.zone_start_time else {
+            info!("No running zone to extend");
+            return;
+        };
+        // A later start time means a later end
+        self.zone_start_time = Some(start + extra);
+        info!("Current step extended by {} seconds", seconds);
+        self.report_progress();
+    }
+
+    // Report where the running program is in its sequence
+    // The times are unknown while the program is paused.
+    fn report_progress(&self) {
+        let Some(index) = self.current_zone_index else {
+            return;
+        };
+        let steps = self.run_plan.get(index..).unwrap_or_default();
+
+        // Zone action of the current step, or of the next one while waiting
+        let (zone_index, cycle, cycles) = steps
+            .iter()
+            .find_map(|step| match step {
+                RunStep::Water {
+                    zone_index,
+                    cycle,
+                    cycles,
+                    ..
+                } => Some((*zone_index, *cycle, *cycles)),
+                _ => None,
+            })
+            .unwrap_or((0, 1, 1));
+
+        let (zone_started_at, zone_ends_at, program_ends_at) =
+            match (self.zone_start_time, steps.first()) {
+                (Some(start), Some(step)) => {
+                    let zone_end = start + Duration::from_secs(step.duration_seconds());
+                    let rest: u64 = steps[1..].iter().map(|s| s.duration_seconds()).sum();
+                    (
+                        Some(wall_time(start)),
+                        Some(wall_time(zone_end)),
+                        Some(wall_time(zone_end + Duration::from_secs(rest))),
+                    )
                 }
+                _ => (None, None, None),
+            };
+
+        let progress = ProgramProgress {
+            zone_index,
+            zone_count: self.current_program.as_ref().map_or(0, |p| p.zones.len()),
+            cycle,
+            cycles,
+            zone_started_at,
+            zone_ends_at,
+            program_ends_at,
+        };
+        let _ = self.tx.send(BoardEvent::ProgressChanged { progress });
+    }
+
+    // Resume the paused program from its current step with the remaining time
+    fn resume(&mut self) {
+        let Some(pause) = self.paused.take() else {
+            info!("No paused program to resume");
+            return;
+        };
+        let Some(index) = self.current_zone_index else {
+            return;
+        };
+        info!("Program resumed");
+        let _ = self.tx.send(BoardEvent::ProgramResumed);
+        self.enter_step(index, pause.remaining);
+    }
+
+    fn open_zones(&mut self, zone_action: &ZoneAction) {
+        // The zones are not reported started if the relays did not switch
+        if let Err(e) = self.relay_controller.open(zone_action.zone_ids.clone()) {
+            info!("Failed to open zones: {}", e);
+            if let Some(e) = e.downcast_ref::<RelayWriteError>() {
+                self.relay_write_failed(e);
+            }
+            return;
+        }
+        let _ = self.tx.send(BoardEvent::ZoneActionStarted {
+            zone_action: zone_action.clone(),
+        });
+        let _ = self.tx.send(BoardEvent::RunStateChanged {
+            state: RunState::Running,
+        });
+    }
+
+    fn close_zones(&mut self) {
+        if let Err(e) = self.relay_controller.close_zones() {
+            self.relay_write_failed(&e);
+        }
+    }
+
+    // Write the relays switched by the safety cutoff
+    fn flush_relays(&mut self) {
+        if let Err(e) = self.relay_controller.flush() {
+            self.relay_write_failed(&e);
+        }
+    }
+
+    fn relay_write_failed(&self, e: &RelayWriteError) {
+        info!("Failed to switch relays: {}", e);
+        let _ = self.tx.send(BoardEvent::RelayWriteFailed {
+            error: e.to_string(),
+        });
+    }
+
+    // Switch the master valve off after its lag time
+    fn release_master(&mut self) {
+        if let Some(lag) = self.relay_controller.master_lag() {
+            if self.master_off_at.is_none() {
+                self.master_off_at = Some(Instant::now() + lag);
             }
         }
     }
+
+    fn reset(&mut self) {
+        if self.current_program.is_some() {
+            self.clear_journal();
+        }
+        self.current_program = None;
+        self.run_plan.clear();
+        self.current_zone_index = None;
+        self.zone_start_time = None;
+        self.zones_open_at = None;
+        self.paused = None;
+    }
+}
+
+// Wall clock time of an instant
+fn wall_time(at: Instant) -> DateTime<Utc> {
+    let now = Instant::now();
+    if at >= now {
+        Utc::now() + chrono::Duration::from_std(at - now).unwrap_or(chrono::Duration::zero())
+    } else {
+        Utc::now() - chrono::Duration::from_std(now - at).unwrap_or(chrono::Duration::zero())
+    }
+}
+
+// Start time of a step of duration which has remaining time left at the given instant
+// The start is later than the instant if more time is left than the step lasts.
+fn step_start(at: Instant, duration: Duration, remaining: Duration) -> Instant {
+    if remaining >= duration {
+        at + (remaining - duration)
+    } else {
+        at.checked_sub(duration - remaining).unwrap_or(at)
+    }
+}
+
+// Scale every zone duration of a program by the water budget percentage
+fn apply_water_budget(mut program: Program, water_budget: u16) -> Program {
+    for zone in &mut program.zones {
+        zone.duration_seconds = (zone.duration_seconds as i64 * water_budget as i64 / 100) as i32;
+    }
+    program
+}
+
+fn save_relay_config_to_nvs(
+    nvs: &mut EspNvs<NvsDefault>,
+    config: &RelayConfig,
+) -> anyhow::Result<()> {
+    let data = serde_json::to_string(config)?;
+    nvs.set_str("relay_cfg", &data)?;
+    Ok(())
+}
+
+fn load_relay_config_from_nvs(nvs: &EspNvs<NvsDefault>) -> anyhow::Result<Option<RelayConfig>> {
+    let mut buf = vec![0u8; 2048];
+    match nvs.get_str("relay_cfg", &mut buf)? {
+        Some(data) => Ok(Some(serde_json::from_str(data)?)),
+        None => Ok(None),
+    }
+}
+
+fn save_run_journal_to_nvs(
+    nvs: &mut EspNvs<NvsDefault>,
+    journal: &RunJournal,
+) -> anyhow::Result<()> {
+    let data = serde_json::to_string(journal)?;
+    nvs.set_str("run_journal", &data)?;
+    Ok(())
+}
+
+fn load_run_journal_from_nvs(nvs: &EspNvs<NvsDefault>) -> anyhow::Result<Option<RunJournal>> {
+    let mut buf = vec![0u8; 512];
+    match nvs.get_str("run_journal", &mut buf)? {
+        Some(data) => Ok(Some(serde_json::from_str(data)?)),
+        None => Ok(None),
+    }
+}
+
+fn save_relay_layout_to_nvs(
+    nvs: &mut EspNvs<NvsDefault>,
+    layout: &RelayLayout,
+) -> anyhow::Result<()> {
+    let data = serde_json::to_string(layout)?;
+    nvs.set_str("relay_layout", &data)?;
+    Ok(())
+}
+
+fn load_relay_layout_from_nvs(nvs: &EspNvs<NvsDefault>) -> anyhow::Result<Option<RelayLayout>> {
+    let mut buf = vec![0u8; 2048];
+    match nvs.get_str("relay_layout", &mut buf)? {
+        Some(data) => Ok(Some(serde_json::from_str(data)?)),
+        None => Ok(None),
+    }
 }
@@ -1,5 +1,6 @@
-use crate::{BoardEvent, Program, Schedule};
-use chrono::{Datelike, Local, NaiveDateTime, Utc};
+use crate::{relay::RunJournal, BoardEvent, Program, Schedule};
+use board_core::schedule_hash::{ContentHasher, ProgramLine};
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, Utc};
 use crossbeam::channel::{self, Receiver, Sender};
 use crossbeam::select;
 use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
@@ -7,10 +8,33 @@ use log::info;
 use std::thread;
 use std::time::Duration;
 
+// Maximum water budget percentage
+const MAX_WATER_BUDGET: u16 = 200;
+
+// Time passing faster than this between two ticks is a clock correction,
+// the program starts in between are missed runs
+const CLOCK_JUMP_SECONDS: i64 = 10;
+
+// Missed runs are looked up at most this many days back
+const MAX_MISSED_DAYS: i64 = 7;
+
+// The last check time is persisted this often to limit NVS writes
+const CHECKED_SAVE_INTERVAL_SECONDS: i64 = 60;
+
+// The schedule is stored as JSON, so fields added later are read with their defaults
+const SCHEDULE_KEY: &str = "schedule_json";
+// Bincode schedule of older firmware, its layout is unknown so it is not read
+const LEGACY_SCHEDULE_KEY: &str = "schedule_bin";
+
 #[derive(Debug, Clone)]
 pub enum ScheduleCommand {
     UpdateSchedule(Schedule),
     StartProgramById(String),
+    SetRainDelay(Option<DateTime<Utc>>),
+    // Look up the program of an interrupted run
+    RecoverRun(RunJournal),
+    // Drop the schedule and erase it from NVS
+    ClearSchedule,
 }
 
 pub struct ScheduleModule {
@@ -19,6 +43,11 @@ pub struct ScheduleModule {
     schedule: Option<Schedule>,
     next_program_opt: Option<Program>,
     wait_duration: Duration,
+    // Automatic program starts are skipped until this time
+    suspended_until: Option<DateTime<Utc>>,
+    // Program starts up to this time were handled, persisted to find missed runs after boot
+    last_checked: Option<DateTime<Utc>>,
+    last_checked_saved: Option<DateTime<Utc>>,
     nvs: EspNvs<NvsDefault>,
 }
 
@@ -40,20 +69,42 @@ impl ScheduleModule {
             schedule: None,
             next_program_opt,
             wait_duration,
+            suspended_until: None,
+            last_checked: None,
+            last_checked_saved: None,
             nvs,
         };
 
-        res.load_schedule_from_nvs()
-            .expect("Failed to load schedule from NVS");
+        // The server sends the latest schedule after connecting anyway
+        if let Err(e) = res.load_schedule_from_nvs() {
+            info!("Failed to load schedule from NVS: {}", e);
+        }
 
         if let Some(schedule) = &res.schedule {
             let _ = res.tx.send(BoardEvent::ScheduleLoaded {
                 version: schedule.version,
+                hash: Some(schedule.content_hash()),
             });
         } else {
             info!("No schedule found in NVS.");
         }
 
+        // Restore the rain delay, it must survive reboots
+        if let Err(e) = res.load_rain_delay_from_nvs() {
+            info!("Failed to load rain delay from NVS: {}", e);
+        }
+        if res.suspended_until.is_some() {
+            let _ = res.tx.send(BoardEvent::RainDelayChanged {
+                until: res.suspended_until,
+            });
+        }
+
+        // Runs missed while the board was off
+        if let Err(e) = res.load_last_checked_from_nvs() {
+            info!("Failed to load last check time from NVS: {}", e);
+        }
+        res.check_missed_runs();
+
         // Set the initial the next program
         res.set_next_program();
 
@@ -113,7 +164,15 @@ impl ScheduleModule {
                             // Recalculate the next program
                             self.set_next_program();
 
-                            let _ = self.tx.send(BoardEvent::ScheduleUpdated { version: self.schedule.clone().unwrap_or_default().version });
+                            // Report what was actually stored, the server resends the schedule if it differs
+                            let hash = match self.read_schedule_from_nvs() {
+                                Ok(stored) => stored.map(|s| s.content_hash()),
+                                Err(e) => {
+                                    info!("Failed to read back schedule from NVS: {}", e);
+                                    None
+                                }
+                            };
+                            let _ = self.tx.send(BoardEvent::ScheduleUpdated { version: self.schedule.clone().unwrap_or_default().version, hash });
                         }
 
                         Ok(ScheduleCommand::StartProgramById(id)) => {
@@ -123,7 +182,8 @@ impl ScheduleModule {
                                     .iter()
                                     .find(|p| p.id == id)
                                 {
-                                    let _ = self.tx.send(BoardEvent::ProgramStarted { program: prog.clone() });
+                                    let water_budget = schedule.water_budget_for(prog);
+                                    let _ = self.tx.send(BoardEvent::ProgramStarted { program: prog.clone(), water_budget });
                                     info!("Program started by ID: {}", id);
 
                                 } else {
@@ -134,6 +194,23 @@ impl ScheduleModule {
                             }
                         }
 
+                        Ok(ScheduleCommand::SetRainDelay(until)) => {
+                            self.set_rain_delay(until);
+                        }
+
+                        Ok(ScheduleCommand::RecoverRun(journal)) => {
+                            let program = self
+                                .schedule
+                                .as_ref()
+                                .and_then(|s| s.programs.iter().find(|p| p.id == journal.program_id()))
+                                .cloned();
+                            let _ = self.tx.send(BoardEvent::RunRecoveryReady { journal, program });
+                        }
+
+                        Ok(ScheduleCommand::ClearSchedule) => {
+                            self.clear_schedule();
+                        }
+
                         Err(_) => {
                             info!("ScheduleModule command channel closed.");
                             break;
@@ -143,13 +220,36 @@ impl ScheduleModule {
 
                 recv(timer_rx) -> _ => {
                     if let Some(prog) = next_prog_opt.as_ref() {
-                        let _ = self.tx.send(BoardEvent::ProgramStarted { program: prog.clone() });
+                        if self.is_suspended() {
+                            info!("Irrigation suspended until {:?}, skipping program {}", self.suspended_until, prog.id);
+                            continue;
+                        }
+                        let water_budget = self
+                            .schedule
+                            .as_ref()
+                            .map(|s| s.water_budget_for(prog))
+                            .unwrap_or(100);
+                        let _ = self.tx.send(BoardEvent::ProgramStarted { program: prog.clone(), water_budget });
+                        self.mark_checked(true);
                         self.set_next_program();
                         info!("Program started automatically.");
                     }
                 }
 
                 recv(tick_rx) -> _ => {
+                    // Clear the rain delay once it has expired
+                    if self.suspended_until.is_some() && !self.is_suspended() {
+                        info!("Rain delay expired");
+                        self.set_rain_delay(None);
+                    }
+                    // A clock correction may have skipped program starts
+                    let elapsed = self.last_checked.map(|last| (Utc::now() - last).num_seconds());
+                    if elapsed.is_some_and(|s| s > CLOCK_JUMP_SECONDS) {
+                        info!("Clock moved forward by {:?} seconds", elapsed);
+                        self.check_missed_runs();
+                    } else {
+                        self.mark_checked(false);
+                    }
                     // csak hogy életben tartsuk a szálat
                     // ide tehetsz időzített státuszfrissítést is, ha kell
                     continue;
@@ -206,19 +306,163 @@ impl ScheduleModule {
         })
     }
 
+    // Report program starts missed since the last check
+    // The latest missed run is started late if it is still within the catch-up window
+    // of its program. Only one run is caught up, the others would interrupt it.
+    fn check_missed_runs(&mut self) {
+        let now = Utc::now();
+        let Some(last) = self.last_checked else {
+            self.mark_checked(true);
+            return;
+        };
+        // Clock moved backwards, nothing was missed
+        if now <= last {
+            self.mark_checked(true);
+            return;
+        }
+        // Starts skipped by the rain delay are not missed
+        if self.is_suspended() {
+            self.mark_checked(true);
+            return;
+        }
+
+        let mut missed: Vec<(Program, DateTime<Utc>)> = vec![];
+        if let Some(schedule) = &self.schedule {
+            let from = last.max(now - chrono::Duration::days(MAX_MISSED_DAYS));
+            let mut date = from.date_naive();
+            while date <= now.date_naive() {
+                let weekday = date.weekday().number_from_monday() as i8;
+                for prog in &schedule.programs {
+                    if !prog.active || !prog.weekdays.contains(&weekday) {
+                        continue;
+                    }
+                    let start = date.and_time(prog.start_time).and_utc();
+                    if start > from && start <= now {
+                        missed.push((prog.clone(), start));
+                    }
+                }
+                date += chrono::Duration::days(1);
+            }
+        }
+        missed.sort_by_key(|(_, start)| *start);
+
+        let catch_up = missed.last().and_then(|(prog, start)| {
+            let window = chrono::Duration::minutes(prog.catch_up_minutes? as i64);
+            (now - *start <= window).then(|| prog.clone())
+        });
+
+        for (i, (prog, start)) in missed.iter().enumerate() {
+            let caught_up = catch_up.is_some() && i == missed.len() - 1;
+            info!(
+                "Missed run of program {} at {} (caught up: {})",
+                prog.id, start, caught_up
+            );
+            let _ = self.tx.send(BoardEvent::RunMissed {
+                program_id: prog.id.clone(),
+                scheduled_at: *start,
+                caught_up,
+            });
+        }
+
+        if let Some(prog) = catch_up {
+            let water_budget = self
+                .schedule
+                .as_ref()
+                .map(|s| s.water_budget_for(&prog))
+                .unwrap_or(100);
+            let _ = self.tx.send(BoardEvent::ProgramStarted {
+                program: prog,
+                water_budget,
+            });
+        }
+
+        self.mark_checked(true);
+    }
+
+    // Program starts up to now are handled
+    // The time is persisted at most once a minute unless forced.
+    fn mark_checked(&mut self, force: bool) {
+        let now = Utc::now();
+        self.last_checked = Some(now);
+        let due = self.last_checked_saved.map_or(true, |saved| {
+            (now - saved).num_seconds().abs() >= CHECKED_SAVE_INTERVAL_SECONDS
+        });
+        if force || due {
+            if let Err(e) = self.nvs.set_i64("sched_checked", now.timestamp()) {
+                info!("Failed to save last check time to NVS: {}", e);
+            }
+            self.last_checked_saved = Some(now);
+        }
+    }
+
+    fn load_last_checked_from_nvs(&mut self) -> anyhow::Result<()> {
+        self.last_checked = self
+            .nvs
+            .get_i64("sched_checked")?
+            .and_then(|ts| DateTime::from_timestamp(ts, 0));
+        Ok(())
+    }
+
+    fn is_suspended(&self) -> bool {
+        self.suspended_until.is_some_and(|until| Utc::now() < until)
+    }
+
+    // Set or clear the rain delay, persist it and report the change
+    fn set_rain_delay(&mut self, until: Option<DateTime<Utc>>) {
+        self.suspended_until = until;
+        if let Err(e) = self.save_rain_delay_to_nvs() {
+            info!("Failed to save rain delay to NVS: {}", e);
+        }
+        info!("Rain delay set until {:?}", until);
+        let _ = self.tx.send(BoardEvent::RainDelayChanged { until });
+    }
+
+    fn save_rain_delay_to_nvs(&mut self) -> anyhow::Result<()> {
+        match self.suspended_until {
+            Some(until) => self.nvs.set_i64("suspend_until", until.timestamp())?,
+            None => {
+                self.nvs.remove("suspend_until")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn load_rain_delay_from_nvs(&mut self) -> anyhow::Result<()> {
+        self.suspended_until = self
+            .nvs
+            .get_i64("suspend_until")?
+            .and_then(|ts| DateTime::from_timestamp(ts, 0));
+        Ok(())
+    }
+
+    // Forget the schedule, the board waits for the server to send a new one
+    fn clear_schedule(&mut self) {
+        self.schedule = None;
+        if let Err(e) = self.nvs.remove(SCHEDULE_KEY) {
+            info!("Failed to erase schedule from NVS: {}", e);
+        }
+        self.set_next_program();
+        info!("Schedule cleared");
+        let _ = self.tx.send(BoardEvent::ScheduleUpdated {
+            version: 0,
+            hash: None,
+        });
+    }
+
     fn save_schedule_to_nvs(&mut self, schedule: &Schedule) -> anyhow::Result<()> {
-        let data = bincode::serialize(&schedule)?;
-        self.nvs.set_raw("schedule_bin", &data)?;
+        let data = serde_json::to_vec(&schedule)?;
+        self.nvs.set_raw(SCHEDULE_KEY, &data)?;
         info!("Schedule saved to NVS. Version: {}", schedule.version);
 
         Ok(())
     }
 
     fn load_schedule_from_nvs(&mut self) -> anyhow::Result<()> {
-        let mut buf = vec![0u8; 12288];
-        let _ = self.nvs.get_raw("schedule_bin", &mut buf)?;
-        if buf.len() > 0 {
-            let schedule: Schedule = bincode::deserialize(&buf)?;
+        if self.nvs.contains(LEGACY_SCHEDULE_KEY)? {
+            info!("Dropping the schedule stored by an older firmware");
+            self.nvs.remove(LEGACY_SCHEDULE_KEY)?;
+        }
+        if let Some(schedule) = self.read_schedule_from_nvs()? {
             info!("Schedule loaded from NVS. Version: {}", schedule.version);
             self.schedule = Some(schedule);
         } else {
@@ -226,4 +470,57 @@ impl ScheduleModule {
         }
         Ok(())
     }
+
+    fn read_schedule_from_nvs(&self) -> anyhow::Result<Option<Schedule>> {
+        let Some(len) = self.nvs.blob_len(SCHEDULE_KEY)? else {
+            return Ok(None);
+        };
+        let mut buf = vec![0u8; len];
+        match self.nvs.get_raw(SCHEDULE_KEY, &mut buf)? {
+            Some(data) => Ok(Some(serde_json::from_slice(data)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Schedule {
+    // Hash of the schedule content without its version, the server computes the same
+    pub fn content_hash(&self) -> String {
+        let mut hasher = ContentHasher::new(self.water_budget.percent, &self.water_budget.monthly);
+        for program in &self.programs {
+            let start_time = program.start_time.format("%H:%M:%S").to_string();
+            hasher.program(&ProgramLine {
+                id: &program.id,
+                name: &program.name,
+                weekdays: &program.weekdays,
+                start_time: &start_time,
+                active: program.active,
+                water_budget: program.water_budget,
+                inter_zone_delay_seconds: program.inter_zone_delay_seconds,
+                catch_up_minutes: program.catch_up_minutes,
+            });
+            for zone in &program.zones {
+                hasher.zone(zone);
+            }
+        }
+        hasher.finish()
+    }
+
+    // Effective water budget of a program in percent
+    // The program's own budget overrides the global one,
+    // the global one is taken from the current month if monthly values are set.
+    pub fn water_budget_for(&self, program: &Program) -> u16 {
+        let percent = match program.water_budget {
+            Some(percent) => percent,
+            None => {
+                let month = Local::now().month0() as usize;
+                self.water_budget
+                    .monthly
+                    .get(month)
+                    .copied()
+                    .unwrap_or(self.water_budget.percent)
+            }
+        };
+        percent.min(MAX_WATER_BUDGET)
+    }
 }
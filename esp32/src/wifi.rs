@@ -1,4 +1,7 @@
-use std::{thread, time::Duration};
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
 
 use crossbeam::{
     channel::{Receiver, Sender},
@@ -6,47 +9,110 @@ use crossbeam::{
 };
 use esp_idf_svc::{
     hal::task::block_on,
-    wifi::{AsyncWifi, AuthMethod, ClientConfiguration, Configuration, EspWifi},
+    nvs::{EspNvs, EspNvsPartition, NvsDefault},
+    wifi::{AsyncWifi, ClientConfiguration, Configuration, EspWifi},
 };
 use log::{info, warn};
 
-use crate::{BoardEvent, PASSWORD, SSID};
+use crate::config::{self, WifiNetwork};
+use crate::BoardEvent;
+
+// Wait after a round in which no network could be joined, doubled up to the maximum
+const BACKOFF_MIN: Duration = Duration::from_secs(5);
+const BACKOFF_MAX: Duration = Duration::from_secs(5 * 60);
+// A weak connection looks for a stronger known network this often
+const ROAM_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const ROAM_RSSI_THRESHOLD: i8 = -75;
+// Another access point has to be this much stronger to switch to it
+const ROAM_HYSTERESIS: i8 = 10;
+
+// Access point the station is connected to
+pub struct ConnectedAp {
+    pub ssid: String,
+    pub bssid: [u8; 6],
+    pub rssi: i8,
+}
+
+pub fn connected_ap() -> Option<ConnectedAp> {
+    let mut ap_info: esp_idf_svc::sys::wifi_ap_record_t = Default::default();
+    let res = unsafe { esp_idf_svc::sys::esp_wifi_sta_get_ap_info(&mut ap_info) };
+    if res != esp_idf_svc::sys::ESP_OK {
+        return None;
+    }
+    let len = ap_info
+        .ssid
+        .iter()
+        .position(|b| *b == 0)
+        .unwrap_or(ap_info.ssid.len());
+    Some(ConnectedAp {
+        ssid: String::from_utf8_lossy(&ap_info.ssid[..len]).into_owned(),
+        bssid: ap_info.bssid,
+        rssi: ap_info.rssi,
+    })
+}
+
+pub fn format_bssid(bssid: &[u8; 6]) -> String {
+    bssid
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+// Known network with its strongest access point in the last scan
+struct Candidate {
+    network: WifiNetwork,
+    bssid: Option<[u8; 6]>,
+    channel: Option<u8>,
+    rssi: Option<i8>,
+}
 
 pub struct WifiModule {
     wifi: AsyncWifi<EspWifi<'static>>,
+    // Provisioned settings, read on every connect
+    nvs: EspNvs<NvsDefault>,
     tx: Sender<BoardEvent>,
     rx: Receiver<WifiCommand>,
     connected: bool,
     connecting: bool,
+    // Rounds in a row in which no network could be joined
+    failures: u32,
+    retry_at: Option<Instant>,
+    last_roam_check: Instant,
 }
 
 impl WifiModule {
     pub fn new(
         wifi: AsyncWifi<EspWifi<'static>>,
         tx: Sender<BoardEvent>,
+        esp_partition: EspNvsPartition<NvsDefault>,
     ) -> anyhow::Result<(Self, Sender<WifiCommand>)> {
         // Create a new WifiModule instance
         let (module_tx, rx) = crossbeam::channel::unbounded::<WifiCommand>();
 
+        let nvs = config::open_nvs(esp_partition)?;
+
         Ok((
             Self {
                 wifi,
+                nvs,
                 tx,
                 rx,
                 connected: false,
                 connecting: false,
+                failures: 0,
+                retry_at: None,
+                last_roam_check: Instant::now(),
             },
             module_tx,
         ))
     }
 
     // Connect to WiFi
-    // This function is used to connect to a WiFi network.
-    // It takes a mutable reference to a BlockingWifi instance
-    // and returns a Result indicating success or failure.
-    // The function creates a Configuration object with the SSID and password,
-    // sets the configuration on the wifi instance, starts the wifi,
-    // and waits for the network interface to be up.
+    // This function is used to connect to one of the WiFi networks stored in NVS.
+    // It scans for them and tries the strongest first,
+    // the order of the list breaks ties and networks missing from the scan are tried last.
+    // It returns once the network interface is up or every network failed.
     async fn connect_wifi(&mut self) -> anyhow::Result<()> {
         info!("Connecting to WiFi...");
 
@@ -63,34 +129,139 @@ impl WifiModule {
             }
         }
 
-        info!("Connecting to WiFi...");
+        let networks = config::load_wifi(&self.nvs);
+        if networks.is_empty() {
+            anyhow::bail!(
+                "WiFi is not configured, add a network on the console: wifi add <ssid> <password>"
+            );
+        }
+
+        // Scanning needs a started station
+        if !self.wifi.is_started()? {
+            self.wifi
+                .set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
+            self.wifi.start().await?;
+            info!("Wifi started");
+        }
+
+        for candidate in self.scan(networks).await {
+            match self.connect_to(&candidate).await {
+                Ok(()) => {
+                    self.connecting = false;
+                    self.connected = true;
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!("WiFi {} failed: {}", candidate.network.ssid, e);
+                    let _ = self.wifi.disconnect().await;
+                }
+            }
+        }
+        anyhow::bail!("None of the known WiFi networks could be joined")
+    }
+
+    // Known networks, strongest first
+    async fn scan(&mut self, networks: Vec<WifiNetwork>) -> Vec<Candidate> {
+        let aps = match self.wifi.scan().await {
+            Ok(aps) => aps,
+            Err(e) => {
+                warn!("WiFi scan failed: {}", e);
+                Vec::new()
+            }
+        };
+        let mut candidates: Vec<Candidate> = networks
+            .into_iter()
+            .map(|network| {
+                // Mesh nodes and extenders share the SSID
+                let best = aps
+                    .iter()
+                    .filter(|ap| ap.ssid.as_str() == network.ssid)
+                    .max_by_key(|ap| ap.signal_strength);
+                Candidate {
+                    bssid: best.map(|ap| ap.bssid),
+                    channel: best.map(|ap| ap.channel),
+                    rssi: best.map(|ap| ap.signal_strength),
+                    network,
+                }
+            })
+            .collect();
+        // Stable sort, networks not seen in the scan go last
+        candidates.sort_by_key(|c| std::cmp::Reverse(c.rssi));
+        candidates
+    }
+
+    async fn connect_to(&mut self, candidate: &Candidate) -> anyhow::Result<()> {
+        let network = &candidate.network;
+        info!(
+            "Connecting to WiFi {} ({:?} dBm)...",
+            network.ssid, candidate.rssi
+        );
         let wifi_configuration: Configuration = Configuration::Client(ClientConfiguration {
-            ssid: SSID.try_into().unwrap(),
-            bssid: None,
-            auth_method: AuthMethod::WPA2Personal,
-            password: PASSWORD.try_into().unwrap(),
-            channel: None,
+            ssid: network
+                .ssid
+                .as_str()
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("SSID is too long"))?,
+            // Join the strongest access point of the network
+            bssid: candidate.bssid,
+            auth_method: network.auth_method(),
+            password: network
+                .password
+                .as_str()
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("WiFi password is too long"))?,
+            channel: candidate.channel,
             ..Default::default()
         });
 
         self.wifi.set_configuration(&wifi_configuration)?;
 
-        self.wifi.start().await?;
-        info!("Wifi started");
-
         self.wifi.connect().await?;
         info!("Wifi connected");
 
-        self.connecting = false;
-
         self.wifi.wait_netif_up().await?;
         info!("Wifi netif up");
 
-        self.connected = true;
-
         Ok(())
     }
 
+    // Move to a clearly stronger known access point if the current one is weak
+    async fn roam(&mut self) -> anyhow::Result<()> {
+        let Some(current) = connected_ap() else {
+            return Ok(());
+        };
+        if current.rssi >= ROAM_RSSI_THRESHOLD {
+            return Ok(());
+        }
+        let networks = config::load_wifi(&self.nvs);
+        let Some(best) = self.scan(networks).await.into_iter().next() else {
+            return Ok(());
+        };
+        match (best.bssid, best.rssi) {
+            (Some(bssid), Some(rssi))
+                if bssid != current.bssid
+                    && rssi >= current.rssi.saturating_add(ROAM_HYSTERESIS) =>
+            {
+                info!(
+                    "Roaming from {} ({} dBm) to {} ({} dBm)",
+                    current.ssid, current.rssi, best.network.ssid, rssi
+                );
+                self.connecting = true;
+                self.wifi.disconnect().await?;
+                let res = self.connect_to(&best).await;
+                // A failed switch is noticed as a disconnect and retried from the start
+                self.connecting = false;
+                res
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn backoff(&self) -> Duration {
+        let factor = 2u32.pow(self.failures.saturating_sub(1).min(6));
+        (BACKOFF_MIN * factor).min(BACKOFF_MAX)
+    }
+
     pub fn start(self) {
         thread::Builder::new()
             .name("schedule_module".into())
@@ -108,8 +279,22 @@ impl WifiModule {
                     match msg {
                         Ok(WifiCommand::Connect) => {
                             info!("Received Wifi connect command");
-                            if !self.connecting {
-                                let _ = block_on(self.connect_wifi());
+                            if self.retry_at.is_some_and(|at| Instant::now() < at) {
+                                info!("Waiting before the next WiFi attempt");
+                            } else if !self.connecting {
+                                match block_on(self.connect_wifi()) {
+                                    Ok(()) => {
+                                        self.failures = 0;
+                                        self.retry_at = None;
+                                    }
+                                    Err(e) => {
+                                        warn!("WiFi connect failed: {}", e);
+                                        self.failures += 1;
+                                        let backoff = self.backoff();
+                                        info!("Next WiFi attempt in {} seconds", backoff.as_secs());
+                                        self.retry_at = Some(Instant::now() + backoff);
+                                    }
+                                }
                                 // Ensure we reset the connecting state in case of error
                                 self.connecting = false;
                             }
@@ -137,6 +322,12 @@ impl WifiModule {
                             let _ = self.tx.send(BoardEvent::WifiStatusChanged { connected: true });
                             info!("WiFi connected!");
                         }
+                        if self.last_roam_check.elapsed() > ROAM_INTERVAL {
+                            self.last_roam_check = Instant::now();
+                            if let Err(e) = block_on(self.roam()) {
+                                warn!("WiFi roaming failed: {}", e);
+                            }
+                        }
                     }
                 }
             }
@@ -4,6 +4,7 @@ use crossbeam::{
 };
 use esp_idf_svc::{
     io::EspIOError,
+    nvs::{EspNvs, EspNvsPartition, NvsDefault},
     ws::{
         client::{
             EspWebSocketClient, EspWebSocketClientConfig, WebSocketEvent, WebSocketEventType,
@@ -12,14 +13,60 @@ use esp_idf_svc::{
     },
 };
 use log::info;
+use serde::Serialize;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use crate::config::{self, ServerConfig};
+use crate::health::Health;
 use crate::{BoardEvent, BoardInfo, ServerCommand};
 
+// A new server has to answer the hello within this time, otherwise the previous one is used again
+const SERVER_TRIAL_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+
+// Protocol version announced in the hello
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// First frame after connecting, identifies the board
+#[derive(Serialize, Debug, Clone)]
+pub struct Hello {
+    pub device_id: String,
+    pub firmware_version: String,
+    pub build_hash: String,
+    pub protocol_version: u32,
+    pub relay_count: u32,
+    pub capabilities: Capabilities,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Capabilities {
+    pub flow_sensor: bool,
+    pub rain_sensor: bool,
+    pub pause: bool,
+}
+
+// Frames of the board besides BoardInfo
+#[derive(Serialize)]
+enum BoardMessage<'a> {
+    Hello(&'a Hello),
+    Health(&'a Health),
+    // Next part of the firmware image being downloaded
+    FirmwareChunkRequest { version: &'a str, offset: u64 },
+}
+
+// Server settings being tried, stored once the new server answers
+struct ServerTrial {
+    previous: ServerConfig,
+    started_at: Instant,
+}
+
 pub struct WsModule {
-    url: String,
-    token: String,
+    server: ServerConfig,
+    trial: Option<ServerTrial>,
+    nvs: EspNvs<NvsDefault>,
+    hello: Hello,
+    // BoardInfo is sent only after the hello
+    hello_sent: bool,
     tx: Sender<BoardEvent>,
     rx: Receiver<WsCommand>,
     client: Option<EspWebSocketClient<'static>>,
@@ -27,19 +74,29 @@ pub struct WsModule {
 }
 
 impl WsModule {
-    pub fn new(url: String, token: String, tx: Sender<BoardEvent>) -> (Self, Sender<WsCommand>) {
+    pub fn new(
+        hello: Hello,
+        tx: Sender<BoardEvent>,
+        esp_partition: EspNvsPartition<NvsDefault>,
+    ) -> anyhow::Result<(Self, Sender<WsCommand>)> {
         let (module_tx, rx) = crossbeam::channel::unbounded::<WsCommand>();
-        (
+        let nvs = config::open_nvs(esp_partition)?;
+        let server = config::load_server(&nvs);
+        info!("WebSocket server: {}", server.url);
+        Ok((
             WsModule {
-                url,
-                token,
+                server,
+                trial: None,
+                nvs,
+                hello,
+                hello_sent: false,
                 tx,
                 rx,
                 client: None,
                 connecting: false,
             },
             module_tx,
-        )
+        ))
     }
 
     /// Start the WebSocket client
@@ -57,6 +114,7 @@ impl WsModule {
             .name("schedule_module".into())
             .stack_size(8192)
             .spawn(move || loop {
+            self.check_server_trial();
             select! {
                 recv(self.rx) -> msg => {
                     match msg {
@@ -65,7 +123,7 @@ impl WsModule {
                                 WsCommand::NewBoardInfo(new_info) => {
                                     if let Ok(data) = serde_json::to_string(&new_info) {
                                         if let Some(client) = &mut self.client {
-                                            if client.is_connected() {
+                                            if client.is_connected() && self.hello_sent {
                                                 if let Ok(()) = client.send(FrameType::Text(false), data.as_bytes()) {
                                                     info!("BoardInfo sent successfully");
                                                 } else {
@@ -74,7 +132,7 @@ impl WsModule {
                                                 }
                                             } else {
                                                 buffer.push(new_info);
-                                                info!("WebSocket client is not connected, buffering BoardInfo");
+                                                info!("WebSocket client is not connected or not identified, buffering BoardInfo");
                                             }
                                         } else {
                                             buffer.push(new_info);
@@ -84,6 +142,25 @@ impl WsModule {
                                         info!("Failed to serialize BoardInfo to JSON");
                                     }
                                 }
+                                WsCommand::Health(health) => {
+                                    // Health is periodic, a missed report is not buffered
+                                    self.send_health(&health);
+                                }
+                                WsCommand::FirmwareChunkRequest { version, offset } => {
+                                    // Not buffered, the OTA module asks again if the chunk does not arrive
+                                    self.send_message(&BoardMessage::FirmwareChunkRequest { version: &version, offset }, "firmware chunk request");
+                                }
+                                WsCommand::SetServer(server) => {
+                                    self.set_server(server);
+                                }
+                                WsCommand::ServerConfirmed => {
+                                    if self.trial.take().is_some() {
+                                        info!("Server {} confirmed", self.server.url);
+                                        if let Err(e) = config::save_server(&mut self.nvs, &self.server) {
+                                            info!("Failed to save server config to NVS: {}", e);
+                                        }
+                                    }
+                                }
                                 WsCommand::Connect => {
                                     // Optionally handle reconnect logic here
                                     info!("Received Connect command");
@@ -91,7 +168,11 @@ impl WsModule {
                                     let _ = self.connect_ws_with_token();
                                 }
                                 WsCommand::Connected => {
-                                    if !buffer.is_empty() {
+                                    // The hello goes first, the server ignores the board until it arrives
+                                    if !self.hello_sent {
+                                        self.send_hello();
+                                    }
+                                    if self.hello_sent && !buffer.is_empty() {
                                         let drained: Vec<_> = buffer.drain(..).collect();
                                         for info in drained {
                                             if let Ok(data) = serde_json::to_string(&info) {
@@ -119,6 +200,7 @@ impl WsModule {
                                 WsCommand::Disconnected => {
                                     self.client = None;
                                     self.connecting = false;
+                                    self.hello_sent = false;
                                     info!("WebSocket client is disconnected");
                                 }
                             }
@@ -143,6 +225,91 @@ impl WsModule {
             .expect("Failed to spawn schedule thread");
     }
 
+    // Try a new server, the previous one stays in NVS until the new one answers
+    fn set_server(&mut self, server: ServerConfig) {
+        if server == self.server {
+            return;
+        }
+        let previous = match self.trial.take() {
+            Some(trial) => trial.previous,
+            None => self.server.clone(),
+        };
+        info!("Trying server {}", server.url);
+        self.server = server;
+        self.trial = Some(ServerTrial {
+            previous,
+            started_at: Instant::now(),
+        });
+        self.reconnect();
+    }
+
+    // Go back to the previous server if the new one did not answer in time
+    fn check_server_trial(&mut self) {
+        if !self
+            .trial
+            .as_ref()
+            .is_some_and(|trial| trial.started_at.elapsed() > SERVER_TRIAL_TIMEOUT)
+        {
+            return;
+        }
+        if let Some(trial) = self.trial.take() {
+            info!(
+                "Server {} did not answer, falling back to {}",
+                self.server.url, trial.previous.url
+            );
+            let _ = self.tx.send(BoardEvent::ServerConfigRejected {
+                url: self.server.url.clone(),
+            });
+            self.server = trial.previous;
+            self.reconnect();
+        }
+    }
+
+    fn reconnect(&mut self) {
+        self.client = None;
+        self.connecting = false;
+        self.hello_sent = false;
+        let _ = self.connect_ws_with_token();
+    }
+
+    fn send_hello(&mut self) {
+        let Ok(data) = serde_json::to_string(&BoardMessage::Hello(&self.hello)) else {
+            info!("Failed to serialize hello to JSON");
+            return;
+        };
+        if let Some(client) = &mut self.client {
+            if client.is_connected() {
+                if let Ok(()) = client.send(FrameType::Text(false), data.as_bytes()) {
+                    info!("Hello sent successfully");
+                    self.hello_sent = true;
+                } else {
+                    info!("Failed to send hello, will retry on the next connect");
+                }
+            }
+        }
+    }
+
+    fn send_health(&mut self, health: &Health) {
+        self.send_message(&BoardMessage::Health(health), "health");
+    }
+
+    // Send a message if the board is identified, drop it otherwise
+    fn send_message(&mut self, message: &BoardMessage, what: &str) {
+        let Ok(data) = serde_json::to_string(message) else {
+            info!("Failed to serialize {} to JSON", what);
+            return;
+        };
+        if let Some(client) = &mut self.client {
+            if client.is_connected() && self.hello_sent {
+                if let Ok(()) = client.send(FrameType::Text(false), data.as_bytes()) {
+                    info!("Sent {} successfully", what);
+                } else {
+                    info!("Failed to send {}, dropping it", what);
+                }
+            }
+        }
+    }
+
     fn connect_ws_with_token(&mut self) -> Result<(), EspIOError> {
         if self.connecting {
             info!("WebSocket client is already connecting, skipping new connection attempt");
@@ -150,10 +317,11 @@ impl WsModule {
         }
 
         self.client = None; // Reset client before connecting
+        self.hello_sent = false;
 
         self.connecting = true;
 
-        let headers = format!("auth_token: {}\r\n", &self.token);
+        let headers = format!("auth_token: {}\r\n", &self.server.token);
 
         // Connect websocket
         let config = EspWebSocketClientConfig {
@@ -165,7 +333,7 @@ impl WsModule {
 
         let tx_clone = self.tx.clone();
 
-        let client = EspWebSocketClient::new(&self.url, &config, timeout, move |event| {
+        let client = EspWebSocketClient::new(&self.server.url, &config, timeout, move |event| {
             handle_event(&tx_clone, event)
         });
 
@@ -194,6 +362,12 @@ impl WsModule {
 
 pub enum WsCommand {
     NewBoardInfo(BoardInfo),
+    Health(Health),
+    FirmwareChunkRequest { version: String, offset: u64 },
+    // Switch to another server, kept only if it answers the hello
+    SetServer(ServerConfig),
+    // The server answered the hello
+    ServerConfirmed,
     Connect,
     Connected,
     Disconnected,
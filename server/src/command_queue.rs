@@ -0,0 +1,336 @@
+use chrono::{DateTime, SecondsFormat, TimeDelta, Utc};
+use log::info;
+use mongodb::bson::doc;
+use rocket::futures::SinkExt;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{State, get, post};
+use rocket_ws as ws;
+use serde::{Deserialize, Serialize};
+use tokio_stream::StreamExt;
+
+use crate::{AppState, BoardDetails, ClientCommand, REDACTED, ServerCommand, to_server_command};
+
+// Time to live of a queued command if not given
+pub const DEFAULT_TTL_SECONDS: u64 = 24 * 60 * 60;
+// Longest time to live a command can be queued with
+pub const MAX_TTL_SECONDS: u64 = 90 * 24 * 60 * 60;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum QueueStatus {
+    Pending,
+    Delivered,
+    // The board did not connect before the TTL passed
+    Expired,
+}
+
+// Websocket token of a queued server config, only kept until the board got it
+const TOKEN_PATH: &str = "command.SetServerConfig.token";
+
+// Command waiting for a board to connect
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct QueuedCommand {
+    pub id: String,
+    pub device_id: String,
+    pub command: ServerCommand,
+    pub status: QueueStatus,
+    pub created_at: String,
+    pub expires_at: String,
+    #[serde(default)]
+    pub delivered_at: Option<String>,
+    #[serde(default)]
+    pub expired_at: Option<String>,
+}
+
+impl QueuedCommand {
+    // Queued command as returned by the API
+    pub fn redacted(self) -> Self {
+        Self {
+            command: self.command.redacted(),
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueueCommandInput {
+    command: ClientCommand,
+    #[serde(default)]
+    ttl_seconds: Option<u64>,
+}
+
+// Fixed width timestamps, so they can be compared and sorted as strings in MongoDB
+fn timestamp(datetime: DateTime<Utc>) -> String {
+    datetime.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+// Expiry of a command queued now with the given TTL
+// TTLs over the maximum are rejected instead of overflowing the time.
+pub fn expires_at(now: DateTime<Utc>, ttl_seconds: u64) -> Result<DateTime<Utc>, Status> {
+    if ttl_seconds > MAX_TTL_SECONDS {
+        return Err(Status::BadRequest);
+    }
+    TimeDelta::try_seconds(ttl_seconds as i64)
+        .and_then(|ttl| now.checked_add_signed(ttl))
+        .ok_or(Status::BadRequest)
+}
+
+fn collection(client: &mongodb::Client) -> mongodb::Collection<QueuedCommand> {
+    client
+        .database("sis")
+        .collection::<QueuedCommand>("command_queue")
+}
+
+// Mark the pending commands of the board expired once their TTL has passed
+async fn expire_commands(client: &mongodb::Client, device_id: &str) -> mongodb::error::Result<()> {
+    let now = timestamp(Utc::now());
+    let res = collection(client)
+        .update_many(
+            doc! {
+                "device_id": device_id,
+                "status": "Pending",
+                "expires_at": { "$lte": &now },
+            },
+            doc! { "$set": { "status": "Expired", "expired_at": &now } },
+        )
+        .await?;
+    if res.modified_count > 0 {
+        info!(
+            "{} queued commands of {} expired",
+            res.modified_count, device_id
+        );
+    }
+    clear_tokens(client, device_id).await
+}
+
+// Drop the tokens of the server configs which were delivered or expired
+async fn clear_tokens(client: &mongodb::Client, device_id: &str) -> mongodb::error::Result<()> {
+    collection(client)
+        .update_many(
+            doc! {
+                "device_id": device_id,
+                "status": { "$ne": "Pending" },
+                TOKEN_PATH: { "$exists": true, "$ne": REDACTED },
+            },
+            doc! { "$set": { TOKEN_PATH: REDACTED } },
+        )
+        .await?;
+    Ok(())
+}
+
+// Send the pending commands of the board in the order they were queued
+// A command is claimed before it is sent, so a second connection of the board
+// does not send it again. It is pending again if it could not be sent.
+pub async fn deliver_queued(
+    client: &mongodb::Client,
+    device_id: &str,
+    stream: &mut ws::stream::DuplexStream,
+) -> ws::result::Result<()> {
+    if let Err(e) = expire_commands(client, device_id).await {
+        info!("MongoDB update error: {:?}", e);
+        return Ok(());
+    }
+    let pending = collection(client)
+        .find(doc! { "device_id": device_id, "status": "Pending" })
+        .sort(doc! { "created_at": 1, "_id": 1 })
+        .await;
+    let mut cursor = match pending {
+        Ok(cursor) => cursor,
+        Err(e) => {
+            info!("MongoDB find error: {:?}", e);
+            return Ok(());
+        }
+    };
+    let mut commands = Vec::new();
+    while let Some(command) = cursor.next().await {
+        match command {
+            Ok(command) => commands.push(command),
+            // A command stored by another server version must not block the rest
+            Err(e) => info!(
+                "Skipping unreadable queued command of {}: {:?}",
+                device_id, e
+            ),
+        }
+    }
+
+    for queued in commands {
+        let claimed = collection(client)
+            .find_one_and_update(
+                doc! { "id": &queued.id, "status": "Pending" },
+                doc! { "$set": {
+                    "status": "Delivered",
+                    "delivered_at": timestamp(Utc::now()),
+                } },
+            )
+            .await;
+        match claimed {
+            Ok(Some(_)) => (),
+            // Sent by another connection meanwhile
+            Ok(None) => continue,
+            Err(e) => {
+                info!("MongoDB update error: {:?}", e);
+                continue;
+            }
+        }
+
+        let json = serde_json::to_string(&queued.command).unwrap();
+        if let Err(e) = stream.send(ws::Message::Text(json)).await {
+            let _ = collection(client)
+                .update_one(
+                    doc! { "id": &queued.id, "status": "Delivered" },
+                    doc! { "$set": { "status": "Pending", "delivered_at": null } },
+                )
+                .await
+                .map_err(|e| info!("MongoDB update error: {:?}", e));
+            return Err(e);
+        }
+        info!(
+            "Sent queued command to {}: {}",
+            device_id,
+            queued.command.name()
+        );
+        if let ServerCommand::SetServerConfig { .. } = queued.command
+            && let Err(e) = clear_tokens(client, device_id).await
+        {
+            info!("MongoDB update error: {:?}", e);
+        }
+    }
+    Ok(())
+}
+
+// Store a command for a board and notify its connection
+pub async fn enqueue(
+    state: &AppState,
+    device_id: &str,
+    command: ServerCommand,
+    expires_at: DateTime<Utc>,
+) -> mongodb::error::Result<QueuedCommand> {
+    let now = Utc::now();
+    let queued = QueuedCommand {
+        id: uuid::Uuid::new_v4().to_string(),
+        device_id: device_id.to_string(),
+        command,
+        status: QueueStatus::Pending,
+        created_at: timestamp(now),
+        expires_at: timestamp(expires_at),
+        delivered_at: None,
+        expired_at: None,
+    };
+    collection(&state.mongo_client)
+        .insert_one(queued.clone())
+        .await?;
+
+    // Nobody listens while the board is offline
+    let _ = state.queue_tx.send(device_id.to_string());
+    Ok(queued)
+}
+
+// Queue a command for a board
+// It is delivered right away if the board is online, otherwise when it connects.
+#[post("/devices/<device_id>/commands", data = "<input>")]
+pub async fn queue_command(
+    state: &State<AppState>,
+    device_id: String,
+    input: Json<QueueCommandInput>,
+) -> Result<Json<QueuedCommand>, Status> {
+    let input = input.into_inner();
+    let expires_at = expires_at(Utc::now(), input.ttl_seconds.unwrap_or(DEFAULT_TTL_SECONDS))?;
+    state
+        .mongo_client
+        .database("sis")
+        .collection::<BoardDetails>("boards")
+        .find_one(doc! { "device_id": &device_id })
+        .await
+        .map_err(|_| Status::InternalServerError)?
+        .ok_or(Status::NotFound)?;
+
+    let command = to_server_command(&state.mongo_client, input.command).await?;
+    let queued = enqueue(state, &device_id, command, expires_at)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    Ok(Json(queued.redacted()))
+}
+
+// List the queued commands of a board, oldest first
+#[get("/devices/<device_id>/commands")]
+pub async fn list_queued_commands(
+    state: &State<AppState>,
+    device_id: String,
+) -> Result<Json<Vec<QueuedCommand>>, Status> {
+    expire_commands(&state.mongo_client, &device_id)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let mut cursor = collection(&state.mongo_client)
+        .find(doc! { "device_id": &device_id })
+        .sort(doc! { "created_at": 1, "_id": 1 })
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let mut commands = Vec::new();
+    while let Some(command) = cursor.next().await {
+        commands.push(command.map_err(|_| Status::InternalServerError)?.redacted());
+    }
+    Ok(Json(commands))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ttl_is_added_to_now() {
+        let now = Utc::now();
+        assert_eq!(expires_at(now, 0), Ok(now));
+        assert_eq!(
+            expires_at(now, DEFAULT_TTL_SECONDS),
+            Ok(now + TimeDelta::days(1))
+        );
+        assert!(expires_at(now, MAX_TTL_SECONDS).is_ok());
+    }
+
+    #[test]
+    fn server_config_token_is_redacted() {
+        let queued = QueuedCommand {
+            id: "1".to_string(),
+            device_id: "board".to_string(),
+            command: ServerCommand::SetServerConfig {
+                device_id: "board".to_string(),
+                url: "wss://example.com/api/websocket".to_string(),
+                token: "secret".to_string(),
+            },
+            status: QueueStatus::Pending,
+            created_at: timestamp(Utc::now()),
+            expires_at: timestamp(Utc::now()),
+            delivered_at: None,
+            expired_at: None,
+        };
+        let json = serde_json::to_string(&queued.clone().redacted()).unwrap();
+        assert!(!json.contains("secret"));
+        assert!(json.contains("wss://example.com/api/websocket"));
+
+        // The token is stored under TOKEN_PATH until it is cleared
+        let mut stored = mongodb::bson::to_document(&queued).unwrap();
+        for key in TOKEN_PATH.split('.').take(2) {
+            stored = stored.get_document(key).unwrap().clone();
+        }
+        assert_eq!(stored.get_str("token"), Ok("secret"));
+    }
+
+    #[test]
+    fn oversized_ttl_is_rejected() {
+        let now = Utc::now();
+        assert_eq!(
+            expires_at(now, MAX_TTL_SECONDS + 1),
+            Err(Status::BadRequest)
+        );
+        assert_eq!(
+            expires_at(now, i64::MAX as u64 + 1),
+            Err(Status::BadRequest)
+        );
+        assert_eq!(expires_at(now, u64::MAX), Err(Status::BadRequest));
+        // Near the end of the representable time even a valid TTL overflows
+        assert_eq!(
+            expires_at(DateTime::<Utc>::MAX_UTC, 1),
+            Err(Status::BadRequest)
+        );
+    }
+}
@@ -0,0 +1,685 @@
+use chrono::{DateTime, Datelike, SecondsFormat, Utc};
+use log::info;
+use mongodb::bson::{self, doc};
+use rocket::State;
+use rocket::get;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio_stream::StreamExt;
+
+use crate::{
+    AppState, BoardDetails, BoardInfo, Program, Schedule, device_of_zone, parse_start_time,
+};
+
+// How often the reconciliation job runs
+const CHECK_INTERVAL_SECONDS: u64 = 10 * 60;
+// Expected starts are checked this long after they were due,
+// so late and caught up runs are found and most runs have ended
+const GRACE_MINUTES: i64 = 3 * 60;
+// A run starting more than this after its expected start is late
+const LATE_MINUTES: i64 = 5;
+// A run lasting less than this percent of its watering time was cut short
+const TRUNCATED_PERCENT: i64 = 90;
+// Expected starts are checked at most this many days back
+const MAX_LOOKBACK_DAYS: i64 = 7;
+
+// Program run reported by a board
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RunRecord {
+    pub device_id: String,
+    pub program_id: String,
+    pub started_at: String,
+    pub ended_at: Option<String>,
+    // Effective water budget the board ran the program with
+    pub water_budget: Option<u16>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum ComplianceKind {
+    // Expected start without a run
+    Missed,
+    // Run started later than expected
+    Late,
+    // Run ended before watering all its zones
+    Truncated,
+    // Run without an expected start, e.g. started manually
+    Unexpected,
+}
+
+// Difference between the schedule and the runs of a board
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ComplianceIssue {
+    pub id: String,
+    pub device_id: String,
+    pub program_id: String,
+    pub kind: ComplianceKind,
+    // Expected start, or the start of the run if it was unexpected
+    pub datetime: String,
+    pub expected_at: Option<String>,
+    pub started_at: Option<String>,
+    pub ended_at: Option<String>,
+    pub detected_at: String,
+}
+
+// Rain delay of a board, program starts from set_at until the delay
+// expired or was cleared were skipped on purpose
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RainDelayRecord {
+    pub device_id: String,
+    pub set_at: String,
+    pub until: String,
+    pub cleared_at: Option<String>,
+}
+
+// Expected starts are checked up to this time
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct ComplianceState {
+    checked_until: String,
+}
+
+// Fixed width timestamps, so they can be compared as strings in MongoDB
+fn timestamp(datetime: DateTime<Utc>) -> String {
+    datetime.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn parse_datetime(datetime: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(datetime)
+        .ok()
+        .map(|d| d.with_timezone(&Utc))
+}
+
+// Update the runs of a board from its running program
+// Open runs of other programs are ended, and a run is opened for
+// the running program unless it is already open.
+pub async fn record_run_state(client: &mongodb::Client, board_info: &BoardInfo) {
+    let collection = client.database("sis").collection::<RunRecord>("runs");
+    let at = timestamp(parse_datetime(&board_info.datetime).unwrap_or_else(Utc::now));
+
+    let mut filter = doc! { "device_id": &board_info.device_id, "ended_at": null };
+    if let Some(program_id) = &board_info.running_program {
+        filter.insert("program_id", doc! { "$ne": program_id });
+    }
+    let _ = collection
+        .update_many(filter, doc! { "$set": { "ended_at": &at } })
+        .await
+        .map_err(|e| info!("MongoDB update error: {:?}", e));
+
+    let Some(program_id) = &board_info.running_program else {
+        return;
+    };
+    let open = doc! {
+        "device_id": &board_info.device_id,
+        "program_id": program_id,
+        "ended_at": null,
+    };
+    match collection.find_one(open).await {
+        Ok(Some(_)) => (),
+        Ok(None) => {
+            let run = RunRecord {
+                device_id: board_info.device_id.clone(),
+                program_id: program_id.clone(),
+                started_at: at,
+                ended_at: None,
+                water_budget: board_info.water_budget,
+            };
+            let _ = collection
+                .insert_one(run)
+                .await
+                .map_err(|e| info!("MongoDB insert error: {:?}", e));
+        }
+        Err(e) => info!("MongoDB find error: {:?}", e),
+    }
+}
+
+// Update the rain delays of a board from its suspended until time
+// The open delay is ended when it changed, and a new one is opened
+// if the board is suspended.
+pub async fn record_rain_delay(client: &mongodb::Client, board_info: &BoardInfo) {
+    let collection = client
+        .database("sis")
+        .collection::<RainDelayRecord>("rain_delays");
+    let now = parse_datetime(&board_info.datetime).unwrap_or_else(Utc::now);
+    let at = timestamp(now);
+    let until = board_info
+        .suspended_until
+        .as_deref()
+        .and_then(parse_datetime)
+        .filter(|until| *until > now)
+        .map(timestamp);
+
+    let open = doc! { "device_id": &board_info.device_id, "cleared_at": null };
+    let open = match collection.find_one(open).await {
+        Ok(open) => open,
+        Err(e) => {
+            info!("MongoDB find error: {:?}", e);
+            return;
+        }
+    };
+    if let Some(open) = open {
+        if until.as_ref() == Some(&open.until) {
+            return;
+        }
+        // An expired delay ended at its until time
+        let cleared_at = at.clone().min(open.until.clone());
+        let _ = collection
+            .update_one(
+                doc! { "device_id": &board_info.device_id, "set_at": &open.set_at },
+                doc! { "$set": { "cleared_at": cleared_at } },
+            )
+            .await
+            .map_err(|e| info!("MongoDB update error: {:?}", e));
+    }
+
+    let Some(until) = until else {
+        return;
+    };
+    let delay = RainDelayRecord {
+        device_id: board_info.device_id.clone(),
+        set_at: at,
+        until,
+        cleared_at: None,
+    };
+    let _ = collection
+        .insert_one(delay)
+        .await
+        .map_err(|e| info!("MongoDB insert error: {:?}", e));
+}
+
+// Run the reconciliation periodically in the background
+pub fn spawn_reconciliation(client: mongodb::Client) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(CHECK_INTERVAL_SECONDS));
+        loop {
+            interval.tick().await;
+            if let Err(e) = reconcile(&client).await {
+                info!("Compliance check failed: {:?}", e);
+            }
+        }
+    });
+}
+
+// Compare the expected starts since the last check with the reported runs
+// Expected starts come from the current schedule, the programs of a board
+// are the ones with at least one of its zones.
+async fn reconcile(client: &mongodb::Client) -> mongodb::error::Result<()> {
+    let db = client.database("sis");
+    let state_collection = db.collection::<ComplianceState>("compliance_state");
+
+    let until = Utc::now() - chrono::Duration::minutes(GRACE_MINUTES);
+    let from = match state_collection.find_one(doc! {}).await? {
+        Some(state) => parse_datetime(&state.checked_until)
+            .unwrap_or(until)
+            .max(until - chrono::Duration::days(MAX_LOOKBACK_DAYS)),
+        // Nothing to compare with before the first check
+        None => until,
+    };
+
+    if from < until {
+        let schedule = db
+            .collection::<Schedule>("schedule")
+            .find_one(doc! {})
+            .await?;
+        let mut boards = db
+            .collection::<BoardDetails>("boards")
+            .find(doc! {})
+            .await?;
+        while let Some(board) = boards.next().await {
+            let board = board?;
+            let programs: Vec<&Program> = schedule
+                .iter()
+                .flat_map(|s| s.programs.iter())
+                .filter(|p| {
+                    p.zones
+                        .iter()
+                        .flat_map(|z| z.zone_ids.iter())
+                        .any(|id| device_of_zone(id) == board.device_id)
+                })
+                .collect();
+            for issue in reconcile_board(client, &board, &programs, from, until).await? {
+                info!("Compliance issue: {:?}", issue);
+                db.collection::<ComplianceIssue>("compliance")
+                    .replace_one(doc! { "id": &issue.id }, issue)
+                    .upsert(true)
+                    .await?;
+            }
+        }
+    }
+
+    let state = ComplianceState {
+        checked_until: timestamp(until.max(from)),
+    };
+    state_collection
+        .replace_one(doc! {}, state)
+        .upsert(true)
+        .await?;
+    Ok(())
+}
+
+async fn reconcile_board(
+    client: &mongodb::Client,
+    board: &BoardDetails,
+    programs: &[&Program],
+    from: DateTime<Utc>,
+    until: DateTime<Utc>,
+) -> mongodb::error::Result<Vec<ComplianceIssue>> {
+    let db = client.database("sis");
+    let grace = chrono::Duration::minutes(GRACE_MINUTES);
+
+    let filter = doc! {
+        "device_id": &board.device_id,
+        "started_at": { "$gt": timestamp(from - grace), "$lte": timestamp(until + grace) },
+    };
+    let mut cursor = db.collection::<RunRecord>("runs").find(filter).await?;
+    let mut runs = Vec::new();
+    while let Some(run) = cursor.next().await {
+        let run = run?;
+        if let Some(started_at) = parse_datetime(&run.started_at) {
+            runs.push((run, started_at));
+        }
+    }
+
+    // Rain delays which ended after from, open ones included
+    let filter = doc! {
+        "device_id": &board.device_id,
+        "until": { "$gt": timestamp(from) },
+        "$or": [ { "cleared_at": null }, { "cleared_at": { "$gt": timestamp(from) } } ],
+    };
+    let mut cursor = db
+        .collection::<RainDelayRecord>("rain_delays")
+        .find(filter)
+        .await?;
+    let mut rain_delays = Vec::new();
+    while let Some(delay) = cursor.next().await {
+        let delay = delay?;
+        let end = delay.cleared_at.as_deref().unwrap_or(&delay.until);
+        if let (Some(set_at), Some(end)) = (parse_datetime(&delay.set_at), parse_datetime(end)) {
+            rain_delays.push((set_at, end));
+        }
+    }
+
+    Ok(find_issues(
+        &board.device_id,
+        programs,
+        &runs,
+        &rain_delays,
+        from,
+        until,
+        Utc::now(),
+    ))
+}
+
+// Compare the expected starts after from, up to until with the runs of a board
+// Starts inside a rain delay, from its set time until it ended, are not missed.
+fn find_issues(
+    device_id: &str,
+    programs: &[&Program],
+    runs: &[(RunRecord, DateTime<Utc>)],
+    rain_delays: &[(DateTime<Utc>, DateTime<Utc>)],
+    from: DateTime<Utc>,
+    until: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Vec<ComplianceIssue> {
+    let grace = chrono::Duration::minutes(GRACE_MINUTES);
+    // A board may start a program a bit early if its clock is off
+    let early = chrono::Duration::minutes(1);
+
+    let detected_at = timestamp(now);
+    let mut issues = Vec::new();
+    let mut issue = |program_id: &str,
+                     kind: ComplianceKind,
+                     expected_at: Option<DateTime<Utc>>,
+                     run: Option<&(RunRecord, DateTime<Utc>)>| {
+        let datetime = timestamp(expected_at.or(run.map(|(_, s)| *s)).unwrap_or(until));
+        issues.push(ComplianceIssue {
+            id: format!("{}/{}/{:?}/{}", device_id, program_id, kind, datetime),
+            device_id: device_id.to_string(),
+            program_id: program_id.to_string(),
+            kind,
+            datetime,
+            expected_at: expected_at.map(timestamp),
+            started_at: run.map(|(r, _)| r.started_at.clone()),
+            ended_at: run.and_then(|(r, _)| r.ended_at.clone()),
+            detected_at: detected_at.clone(),
+        });
+    };
+
+    for (program, expected_at) in expected_starts(programs, from, until) {
+        if rain_delays
+            .iter()
+            .any(|(set_at, end)| expected_at >= *set_at && expected_at < *end)
+        {
+            continue;
+        }
+        let run = runs.iter().find(|(r, started_at)| {
+            r.program_id == program.id
+                && *started_at >= expected_at - early
+                && *started_at <= expected_at + grace
+        });
+        let Some(run) = run else {
+            issue(&program.id, ComplianceKind::Missed, Some(expected_at), None);
+            continue;
+        };
+        let (record, started_at) = run;
+        if *started_at - expected_at > chrono::Duration::minutes(LATE_MINUTES) {
+            issue(
+                &program.id,
+                ComplianceKind::Late,
+                Some(expected_at),
+                Some(run),
+            );
+        }
+        // Pauses and delays only make a run longer, so a run shorter
+        // than its watering time was cut short
+        let ended_at = record.ended_at.as_deref().and_then(parse_datetime);
+        if let Some(ended_at) = ended_at {
+            let budget = record.water_budget.unwrap_or(100) as i64;
+            let watering: i64 = program
+                .zones
+                .iter()
+                .map(|z| z.duration_seconds as i64)
+                .sum::<i64>()
+                * budget
+                / 100;
+            let lasted = (ended_at - *started_at).num_seconds();
+            if lasted * 100 < watering * TRUNCATED_PERCENT {
+                issue(
+                    &program.id,
+                    ComplianceKind::Truncated,
+                    Some(expected_at),
+                    Some(run),
+                );
+            }
+        }
+    }
+
+    for run in runs.iter() {
+        let (record, started_at) = run;
+        if *started_at <= from || *started_at > until {
+            continue;
+        }
+        let program = programs
+            .iter()
+            .copied()
+            .filter(|p| p.id == record.program_id);
+        let expected = expected_starts(
+            &program.collect::<Vec<_>>(),
+            *started_at - grace,
+            *started_at + early,
+        );
+        if expected.is_empty() {
+            issue(
+                &record.program_id,
+                ComplianceKind::Unexpected,
+                None,
+                Some(run),
+            );
+        }
+    }
+
+    issues
+}
+
+// Starts of the active programs after from, up to until
+fn expected_starts<'a>(
+    programs: &[&'a Program],
+    from: DateTime<Utc>,
+    until: DateTime<Utc>,
+) -> Vec<(&'a Program, DateTime<Utc>)> {
+    let mut starts = Vec::new();
+    let mut date = from.date_naive();
+    while date <= until.date_naive() {
+        let weekday = date.weekday().number_from_monday() as u8;
+        for program in programs {
+            if !program.active || !program.weekdays.contains(&weekday) {
+                continue;
+            }
+            let Some(start_time) = parse_start_time(&program.start_time) else {
+                continue;
+            };
+            let start = date.and_time(start_time).and_utc();
+            if start > from && start <= until {
+                starts.push((*program, start));
+            }
+        }
+        date += chrono::Duration::days(1);
+    }
+    starts
+}
+
+// List compliance issues, newest first
+// Optionally filtered by board, kind and time range (RFC 3339).
+#[get("/reports/compliance?<device_id>&<kind>&<from>&<to>")]
+pub async fn compliance_report(
+    state: &State<AppState>,
+    device_id: Option<String>,
+    kind: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+) -> Result<Json<Vec<ComplianceIssue>>, Status> {
+    let mut filter = doc! {};
+    if let Some(device_id) = device_id {
+        filter.insert("device_id", device_id);
+    }
+    if let Some(kind) = kind {
+        filter.insert("kind", kind);
+    }
+    let mut range = doc! {};
+    if let Some(from) = from {
+        let from = parse_datetime(&from).ok_or(Status::BadRequest)?;
+        range.insert("$gte", timestamp(from));
+    }
+    if let Some(to) = to {
+        let to = parse_datetime(&to).ok_or(Status::BadRequest)?;
+        range.insert("$lte", timestamp(to));
+    }
+    if !range.is_empty() {
+        filter.insert("datetime", bson::Bson::Document(range));
+    }
+
+    let mut cursor = state
+        .mongo_client
+        .database("sis")
+        .collection::<ComplianceIssue>("compliance")
+        .find(filter)
+        .sort(doc! { "datetime": -1 })
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let mut issues = Vec::new();
+    while let Some(issue) = cursor.next().await {
+        issues.push(issue.map_err(|_| Status::InternalServerError)?);
+    }
+    Ok(Json(issues))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ZoneAction;
+    use chrono::TimeZone;
+
+    fn program(id: &str, weekdays: Vec<u8>, start_time: &str, seconds: u32) -> Program {
+        Program {
+            id: id.to_string(),
+            name: id.to_string(),
+            weekdays,
+            active: true,
+            start_time: start_time.to_string(),
+            zones: vec![ZoneAction {
+                zone_ids: vec!["board/1".to_string()],
+                duration_seconds: seconds,
+                cycles: None,
+                soak_seconds: None,
+            }],
+            water_budget: None,
+            inter_zone_delay_seconds: None,
+            catch_up_minutes: None,
+        }
+    }
+
+    fn at(day: u32, hour: u32, minute: u32, second: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, day, hour, minute, second)
+            .unwrap()
+    }
+
+    fn run(
+        program_id: &str,
+        started_at: DateTime<Utc>,
+        seconds: i64,
+        water_budget: Option<u16>,
+    ) -> (RunRecord, DateTime<Utc>) {
+        let record = RunRecord {
+            device_id: "board".to_string(),
+            program_id: program_id.to_string(),
+            started_at: timestamp(started_at),
+            ended_at: Some(timestamp(started_at + chrono::Duration::seconds(seconds))),
+            water_budget,
+        };
+        (record, started_at)
+    }
+
+    fn kinds(issues: &[ComplianceIssue]) -> Vec<ComplianceKind> {
+        issues.iter().map(|i| i.kind).collect()
+    }
+
+    // 2024-01-01 is a Monday
+    fn check(
+        programs: &[&Program],
+        runs: &[(RunRecord, DateTime<Utc>)],
+        rain_delays: &[(DateTime<Utc>, DateTime<Utc>)],
+    ) -> Vec<ComplianceIssue> {
+        find_issues(
+            "board",
+            programs,
+            runs,
+            rain_delays,
+            at(1, 0, 0, 0),
+            at(1, 23, 0, 0),
+            at(2, 2, 0, 0),
+        )
+    }
+
+    #[test]
+    fn expected_starts_cross_the_week_boundary() {
+        let evening = program("evening", vec![7], "23:30", 600);
+        let morning = program("morning", vec![1], "06:00:00", 600);
+        let starts = expected_starts(
+            &[&evening, &morning],
+            at(1, 0, 0, 0) - chrono::Duration::hours(1),
+            at(1, 7, 0, 0),
+        );
+        let starts: Vec<(&str, DateTime<Utc>)> =
+            starts.iter().map(|(p, s)| (p.id.as_str(), *s)).collect();
+        assert_eq!(
+            starts,
+            vec![
+                ("evening", at(1, 0, 0, 0) - chrono::Duration::minutes(30)),
+                ("morning", at(1, 6, 0, 0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn expected_starts_bounds_and_inactive_programs() {
+        let morning = program("morning", vec![1, 2], "06:00", 600);
+        let mut inactive = program("inactive", vec![1], "06:00", 600);
+        inactive.active = false;
+        // From is exclusive, until is inclusive
+        let starts = expected_starts(&[&morning, &inactive], at(1, 6, 0, 0), at(2, 6, 0, 0));
+        assert_eq!(starts.len(), 1);
+        assert_eq!(starts[0].1, at(2, 6, 0, 0));
+        // Every day of a week is checked once
+        let starts = expected_starts(&[&morning], at(1, 0, 0, 0), at(8, 0, 0, 0));
+        assert_eq!(starts.len(), 2);
+    }
+
+    #[test]
+    fn run_on_time_has_no_issue() {
+        let morning = program("morning", vec![1], "06:00", 600);
+        let issues = check(
+            &[&morning],
+            &[run("morning", at(1, 6, 0, 30), 600, None)],
+            &[],
+        );
+        assert!(issues.is_empty());
+    }
+
+    #[test]
+    fn missed_start() {
+        let morning = program("morning", vec![1], "06:00", 600);
+        let issues = check(&[&morning], &[], &[]);
+        assert_eq!(kinds(&issues), vec![ComplianceKind::Missed]);
+        assert_eq!(issues[0].expected_at, Some(timestamp(at(1, 6, 0, 0))));
+    }
+
+    #[test]
+    fn late_threshold() {
+        let morning = program("morning", vec![1], "06:00", 600);
+        let issues = check(
+            &[&morning],
+            &[run("morning", at(1, 6, 5, 0), 600, None)],
+            &[],
+        );
+        assert!(issues.is_empty());
+        let issues = check(
+            &[&morning],
+            &[run("morning", at(1, 6, 5, 1), 600, None)],
+            &[],
+        );
+        assert_eq!(kinds(&issues), vec![ComplianceKind::Late]);
+    }
+
+    #[test]
+    fn truncated_threshold_with_water_budget() {
+        let morning = program("morning", vec![1], "06:00", 600);
+        let issues = check(
+            &[&morning],
+            &[run("morning", at(1, 6, 0, 0), 540, None)],
+            &[],
+        );
+        assert!(issues.is_empty());
+        let issues = check(
+            &[&morning],
+            &[run("morning", at(1, 6, 0, 0), 539, None)],
+            &[],
+        );
+        assert_eq!(kinds(&issues), vec![ComplianceKind::Truncated]);
+        // Half the water budget is half the watering time
+        let issues = check(
+            &[&morning],
+            &[run("morning", at(1, 6, 0, 0), 270, Some(50))],
+            &[],
+        );
+        assert!(issues.is_empty());
+    }
+
+    #[test]
+    fn unexpected_run() {
+        let morning = program("morning", vec![1], "06:00", 600);
+        let issues = check(
+            &[&morning],
+            &[
+                run("morning", at(1, 6, 0, 0), 600, None),
+                run("morning", at(1, 12, 0, 0), 600, None),
+            ],
+            &[],
+        );
+        assert_eq!(kinds(&issues), vec![ComplianceKind::Unexpected]);
+    }
+
+    #[test]
+    fn only_starts_inside_a_rain_delay_are_excused() {
+        let morning = program("morning", vec![1], "06:00", 600);
+        let evening = program("evening", vec![1], "20:00", 600);
+        let programs = [&morning, &evening];
+        // Set before the morning start, cleared before the evening start
+        let issues = check(&programs, &[], &[(at(1, 5, 0, 0), at(1, 12, 0, 0))]);
+        assert_eq!(kinds(&issues), vec![ComplianceKind::Missed]);
+        assert_eq!(issues[0].program_id, "evening");
+        // Set after the morning start
+        let issues = check(&programs, &[], &[(at(1, 7, 0, 0), at(2, 7, 0, 0))]);
+        assert_eq!(kinds(&issues), vec![ComplianceKind::Missed]);
+        assert_eq!(issues[0].program_id, "morning");
+    }
+}
@@ -0,0 +1,338 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chrono::{SecondsFormat, Utc};
+use log::info;
+use mongodb::bson::{self, doc};
+use rocket::data::{Data, ToByteUnit};
+use rocket::futures::SinkExt;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{State, get, post};
+use rocket_ws as ws;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio_stream::StreamExt;
+
+use crate::command_queue::{self, QueuedCommand};
+use crate::{AppState, BoardDetails, ServerCommand};
+
+// Size of the ota_0/ota_1 app partitions, a larger image does not fit
+const MAX_IMAGE_SIZE: usize = 0x1E0000;
+// Bytes sent in one FirmwareChunk, base64 encoded it still fits the board's websocket buffer
+const CHUNK_SIZE: usize = 2048;
+// First byte of every ESP32 app image
+const IMAGE_MAGIC: u8 = 0xE9;
+// Time an update waits for an offline board
+const DEFAULT_UPDATE_TTL_SECONDS: u64 = 7 * 24 * 60 * 60;
+
+// Uploaded firmware image
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FirmwareRelease {
+    pub version: String,
+    pub size: u64,
+    // Hex encoded SHA-256 of the image, checked by the board before it boots it
+    pub sha256: String,
+    pub chunk_size: u32,
+    pub uploaded_at: String,
+    // Boards the release was deployed to
+    #[serde(default)]
+    pub target_devices: Vec<String>,
+    #[serde(default)]
+    pub deployed_at: Option<String>,
+}
+
+// Part of an image, stored separately so a chunk can be served without loading the image
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct FirmwareChunk {
+    version: String,
+    index: u32,
+    data: bson::Binary,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeployInput {
+    device_ids: Vec<String>,
+    #[serde(default)]
+    ttl_seconds: Option<u64>,
+}
+
+fn timestamp(datetime: chrono::DateTime<Utc>) -> String {
+    datetime.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn releases(client: &mongodb::Client) -> mongodb::Collection<FirmwareRelease> {
+    client
+        .database("sis")
+        .collection::<FirmwareRelease>("firmware")
+}
+
+fn chunks(client: &mongodb::Client) -> mongodb::Collection<FirmwareChunk> {
+    client
+        .database("sis")
+        .collection::<FirmwareChunk>("firmware_chunks")
+}
+
+// Split an image into the chunks it is stored and sent in
+fn split_image(version: &str, image: &[u8], chunk_size: usize) -> Vec<FirmwareChunk> {
+    image
+        .chunks(chunk_size)
+        .enumerate()
+        .map(|(index, data)| FirmwareChunk {
+            version: version.to_string(),
+            index: index as u32,
+            data: bson::Binary {
+                subtype: bson::spec::BinarySubtype::Generic,
+                bytes: data.to_vec(),
+            },
+        })
+        .collect()
+}
+
+// Index of the chunk starting at offset, boards only ask for chunk boundaries
+fn chunk_index(release: &FirmwareRelease, offset: u64) -> Option<u32> {
+    let chunk_size = release.chunk_size as u64;
+    if chunk_size == 0 || !offset.is_multiple_of(chunk_size) || offset >= release.size {
+        return None;
+    }
+    u32::try_from(offset / chunk_size).ok()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Upload a firmware image as the body of the request
+#[post("/firmware/<version>", data = "<image>")]
+pub async fn upload_firmware(
+    state: &State<AppState>,
+    version: String,
+    image: Data<'_>,
+) -> Result<Json<FirmwareRelease>, Status> {
+    let image = image
+        .open(MAX_IMAGE_SIZE.bytes())
+        .into_bytes()
+        .await
+        .map_err(|_| Status::BadRequest)?;
+    if !image.is_complete() {
+        return Err(Status::PayloadTooLarge);
+    }
+    let image = image.into_inner();
+    if image.first() != Some(&IMAGE_MAGIC) {
+        return Err(Status::BadRequest);
+    }
+
+    let existing = releases(&state.mongo_client)
+        .find_one(doc! { "version": &version })
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    if existing.is_some() {
+        return Err(Status::Conflict);
+    }
+
+    let parts = split_image(&version, &image, CHUNK_SIZE);
+    // Chunks left from a failed upload
+    chunks(&state.mongo_client)
+        .delete_many(doc! { "version": &version })
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    chunks(&state.mongo_client)
+        .insert_many(parts)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+
+    // The release is listed only once every chunk is stored
+    let release = FirmwareRelease {
+        version,
+        size: image.len() as u64,
+        sha256: sha256_hex(&image),
+        chunk_size: CHUNK_SIZE as u32,
+        uploaded_at: timestamp(Utc::now()),
+        target_devices: Vec::new(),
+        deployed_at: None,
+    };
+    releases(&state.mongo_client)
+        .insert_one(release.clone())
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    info!(
+        "Firmware {} uploaded, {} bytes",
+        release.version, release.size
+    );
+    Ok(Json(release))
+}
+
+// List the uploaded firmware releases, newest first
+#[get("/firmware")]
+pub async fn list_firmware(state: &State<AppState>) -> Result<Json<Vec<FirmwareRelease>>, Status> {
+    let mut cursor = releases(&state.mongo_client)
+        .find(doc! {})
+        .sort(doc! { "uploaded_at": -1, "_id": -1 })
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let mut list = Vec::new();
+    while let Some(release) = cursor.next().await {
+        list.push(release.map_err(|_| Status::InternalServerError)?);
+    }
+    Ok(Json(list))
+}
+
+// Deploy a release to boards
+// The update is queued, offline boards get it when they connect.
+#[post("/firmware/<version>/deploy", data = "<input>")]
+pub async fn deploy_firmware(
+    state: &State<AppState>,
+    version: String,
+    input: Json<DeployInput>,
+) -> Result<Json<Vec<QueuedCommand>>, Status> {
+    let input = input.into_inner();
+    let expires_at = command_queue::expires_at(
+        Utc::now(),
+        input.ttl_seconds.unwrap_or(DEFAULT_UPDATE_TTL_SECONDS),
+    )?;
+    let release = releases(&state.mongo_client)
+        .find_one(doc! { "version": &version })
+        .await
+        .map_err(|_| Status::InternalServerError)?
+        .ok_or(Status::NotFound)?;
+
+    let boards = state
+        .mongo_client
+        .database("sis")
+        .collection::<BoardDetails>("boards");
+    for device_id in &input.device_ids {
+        boards
+            .find_one(doc! { "device_id": device_id })
+            .await
+            .map_err(|_| Status::InternalServerError)?
+            .ok_or(Status::NotFound)?;
+    }
+
+    releases(&state.mongo_client)
+        .update_one(
+            doc! { "version": &version },
+            doc! {
+                "$addToSet": { "target_devices": { "$each": &input.device_ids } },
+                "$set": { "deployed_at": timestamp(Utc::now()) },
+            },
+        )
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+
+    let mut queued = Vec::new();
+    for device_id in input.device_ids {
+        let command = ServerCommand::UpdateFirmware {
+            device_id: device_id.clone(),
+            version: release.version.clone(),
+            size: release.size,
+            sha256: release.sha256.clone(),
+        };
+        let entry = command_queue::enqueue(state, &device_id, command, expires_at)
+            .await
+            .map_err(|_| Status::InternalServerError)?;
+        queued.push(entry);
+    }
+    Ok(Json(queued))
+}
+
+// Answer a chunk request of a board downloading an update
+// Unknown chunks are not answered, the board gives up after its timeout.
+pub async fn send_chunk(
+    client: &mongodb::Client,
+    version: &str,
+    offset: u64,
+    stream: &mut ws::stream::DuplexStream,
+) -> ws::result::Result<()> {
+    let release = match releases(client).find_one(doc! { "version": version }).await {
+        Ok(Some(release)) => release,
+        Ok(None) => {
+            info!("Chunk of unknown firmware {} requested", version);
+            return Ok(());
+        }
+        Err(e) => {
+            info!("MongoDB find error: {:?}", e);
+            return Ok(());
+        }
+    };
+    let Some(index) = chunk_index(&release, offset) else {
+        info!("Invalid chunk offset {} of firmware {}", offset, version);
+        return Ok(());
+    };
+    let chunk = match chunks(client)
+        .find_one(doc! { "version": version, "index": index })
+        .await
+    {
+        Ok(Some(chunk)) => chunk,
+        Ok(None) => {
+            info!("Chunk {} of firmware {} is missing", index, version);
+            return Ok(());
+        }
+        Err(e) => {
+            info!("MongoDB find error: {:?}", e);
+            return Ok(());
+        }
+    };
+
+    let msg = ServerCommand::FirmwareChunk {
+        version: version.to_string(),
+        offset,
+        data: BASE64.encode(&chunk.data.bytes),
+    };
+    let json = serde_json::to_string(&msg).unwrap();
+    stream.send(ws::Message::Text(json)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn release(image: &[u8]) -> FirmwareRelease {
+        FirmwareRelease {
+            version: "1.2.0".to_string(),
+            size: image.len() as u64,
+            sha256: sha256_hex(image),
+            chunk_size: CHUNK_SIZE as u32,
+            uploaded_at: timestamp(Utc::now()),
+            target_devices: Vec::new(),
+            deployed_at: None,
+        }
+    }
+
+    #[test]
+    fn chunks_reassemble_to_the_image() {
+        // Two full chunks and a short last one
+        let image: Vec<u8> = (0..2 * CHUNK_SIZE + 5).map(|i| (i % 251) as u8).collect();
+        let release = release(&image);
+        let parts = split_image(&release.version, &image, CHUNK_SIZE);
+        assert_eq!(parts.len(), 3);
+
+        // Requested the way the board does, each offset follows the received bytes
+        let mut received = Vec::new();
+        while (received.len() as u64) < release.size {
+            let index = chunk_index(&release, received.len() as u64).unwrap();
+            let chunk = parts.iter().find(|c| c.index == index).unwrap();
+            // Sent base64 encoded in FirmwareChunk
+            let data = BASE64.decode(BASE64.encode(&chunk.data.bytes)).unwrap();
+            received.extend_from_slice(&data);
+        }
+        assert_eq!(received, image);
+        assert_eq!(sha256_hex(&received), release.sha256);
+    }
+
+    #[test]
+    fn invalid_offsets_have_no_chunk() {
+        let image = vec![IMAGE_MAGIC; CHUNK_SIZE + 1];
+        let mut release = release(&image);
+        assert_eq!(chunk_index(&release, 0), Some(0));
+        assert_eq!(chunk_index(&release, CHUNK_SIZE as u64), Some(1));
+        // Not a chunk boundary
+        assert_eq!(chunk_index(&release, 1), None);
+        // Past the end of the image
+        assert_eq!(chunk_index(&release, 2 * CHUNK_SIZE as u64), None);
+        assert_eq!(chunk_index(&release, u64::MAX), None);
+        release.chunk_size = 0;
+        assert_eq!(chunk_index(&release, 0), None);
+    }
+}
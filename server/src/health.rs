@@ -0,0 +1,107 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::info;
+use mongodb::bson::doc;
+use rocket::State;
+use rocket::get;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use serde::{Deserialize, Serialize};
+use tokio_stream::StreamExt;
+
+use crate::AppState;
+
+// Number of samples returned if no limit is given
+const DEFAULT_LIMIT: i64 = 1440;
+
+// Health data reported periodically by a board
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HealthReport {
+    // Board time of the sample
+    pub datetime: String,
+    // Network the board chose, with the access point it is connected to
+    #[serde(default)]
+    pub wifi_ssid: Option<String>,
+    #[serde(default)]
+    pub wifi_bssid: Option<String>,
+    #[serde(default)]
+    pub rssi: Option<i8>,
+    pub free_heap: u32,
+    pub min_free_heap: u32,
+    pub uptime_seconds: u64,
+    pub reset_reason: String,
+    pub sntp_status: String,
+    #[serde(default)]
+    pub rtc_temperature: Option<f32>,
+}
+
+// Stored health sample
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HealthSample {
+    pub device_id: String,
+    // Server time of the sample, fixed width so it can be compared as a string
+    pub received_at: String,
+    #[serde(flatten)]
+    pub report: HealthReport,
+}
+
+fn timestamp(datetime: DateTime<Utc>) -> String {
+    datetime.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn collection(client: &mongodb::Client) -> mongodb::Collection<HealthSample> {
+    client.database("sis").collection::<HealthSample>("health")
+}
+
+// Store a health report of the board
+pub async fn record(client: &mongodb::Client, device_id: &str, report: HealthReport) {
+    let sample = HealthSample {
+        device_id: device_id.to_string(),
+        received_at: timestamp(Utc::now()),
+        report,
+    };
+    let _ = collection(client)
+        .insert_one(sample)
+        .await
+        .map_err(|e| info!("MongoDB insert error: {:?}", e));
+}
+
+// Parse a query bound, accepting any rfc3339 offset
+fn parse_bound(value: &str) -> Result<String, Status> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|d| timestamp(d.with_timezone(&Utc)))
+        .map_err(|_| Status::BadRequest)
+}
+
+// Health samples of a board, newest first
+#[get("/devices/<device_id>/health?<from>&<to>&<limit>")]
+pub async fn device_health(
+    state: &State<AppState>,
+    device_id: String,
+    from: Option<String>,
+    to: Option<String>,
+    limit: Option<i64>,
+) -> Result<Json<Vec<HealthSample>>, Status> {
+    let mut filter = doc! { "device_id": &device_id };
+    let mut range = doc! {};
+    if let Some(from) = from {
+        range.insert("$gte", parse_bound(&from)?);
+    }
+    if let Some(to) = to {
+        range.insert("$lte", parse_bound(&to)?);
+    }
+    if !range.is_empty() {
+        filter.insert("received_at", range);
+    }
+
+    let mut cursor = collection(&state.mongo_client)
+        .find(filter)
+        .sort(doc! { "received_at": -1, "_id": -1 })
+        .limit(limit.unwrap_or(DEFAULT_LIMIT).max(1))
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let mut samples = Vec::new();
+    while let Some(sample) = cursor.next().await {
+        samples.push(sample.map_err(|_| Status::InternalServerError)?);
+    }
+    Ok(Json(samples))
+}
@@ -0,0 +1,156 @@
+use chrono::{SecondsFormat, Utc};
+use log::info;
+use mongodb::bson::doc;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{State, post};
+use serde::{Deserialize, Serialize};
+
+use crate::{AppState, BoardDetails, ServerCommand};
+
+// Time to confirm a maintenance request
+const CONFIRM_TTL_SECONDS: i64 = 2 * 60;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum MaintenanceAction {
+    Reboot,
+    // Erase the schedule stored on the board
+    ClearSchedule,
+    // Erase the schedule, relay and update state of the board and restart it,
+    // Wi-Fi and server settings are kept so the board comes back
+    FactoryReset,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum MaintenanceStatus {
+    // Waiting for the token to be confirmed
+    Pending,
+    // Confirmed and sent to the board
+    Sent,
+}
+
+// Maintenance request, kept as a log of what was sent to which board
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MaintenanceRequest {
+    pub token: String,
+    pub device_id: String,
+    pub action: MaintenanceAction,
+    pub status: MaintenanceStatus,
+    pub created_at: String,
+    pub expires_at: String,
+    #[serde(default)]
+    pub sent_at: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MaintenanceInput {
+    action: MaintenanceAction,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConfirmInput {
+    token: String,
+}
+
+impl MaintenanceRequest {
+    // The token stays on the server, confirming it is what guards the command
+    fn to_server_command(&self) -> ServerCommand {
+        let device_id = self.device_id.clone();
+        match self.action {
+            MaintenanceAction::Reboot => ServerCommand::Reboot { device_id },
+            MaintenanceAction::ClearSchedule => ServerCommand::ClearSchedule { device_id },
+            MaintenanceAction::FactoryReset => ServerCommand::FactoryReset { device_id },
+        }
+    }
+}
+
+// Fixed width timestamps, so they can be compared as strings in MongoDB
+fn timestamp(datetime: chrono::DateTime<Utc>) -> String {
+    datetime.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn collection(client: &mongodb::Client) -> mongodb::Collection<MaintenanceRequest> {
+    client
+        .database("sis")
+        .collection::<MaintenanceRequest>("maintenance")
+}
+
+// Maintenance commands are not queued, the board has to be online
+async fn check_online(state: &AppState, device_id: &str) -> Result<(), Status> {
+    let devices = state.online_devices.lock().await;
+    if devices.iter().any(|b| b.device_id == device_id) {
+        Ok(())
+    } else {
+        Err(Status::Conflict)
+    }
+}
+
+// Request a maintenance command for a board
+// Nothing is sent until the returned token is confirmed.
+#[post("/devices/<device_id>/maintenance", data = "<input>")]
+pub async fn request_maintenance(
+    state: &State<AppState>,
+    device_id: String,
+    input: Json<MaintenanceInput>,
+) -> Result<Json<MaintenanceRequest>, Status> {
+    state
+        .mongo_client
+        .database("sis")
+        .collection::<BoardDetails>("boards")
+        .find_one(doc! { "device_id": &device_id })
+        .await
+        .map_err(|_| Status::InternalServerError)?
+        .ok_or(Status::NotFound)?;
+    check_online(state, &device_id).await?;
+
+    let now = Utc::now();
+    let request = MaintenanceRequest {
+        token: uuid::Uuid::new_v4().to_string(),
+        device_id,
+        action: input.action,
+        status: MaintenanceStatus::Pending,
+        created_at: timestamp(now),
+        expires_at: timestamp(now + chrono::Duration::seconds(CONFIRM_TTL_SECONDS)),
+        sent_at: None,
+    };
+    collection(&state.mongo_client)
+        .insert_one(request.clone())
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    Ok(Json(request))
+}
+
+// Confirm a maintenance request with its token and send the command to the board
+// A token can be used once, before it expires.
+#[post("/devices/<device_id>/maintenance/confirm", data = "<input>")]
+pub async fn confirm_maintenance(
+    state: &State<AppState>,
+    device_id: String,
+    input: Json<ConfirmInput>,
+) -> Result<Json<MaintenanceRequest>, Status> {
+    check_online(state, &device_id).await?;
+
+    let now = timestamp(Utc::now());
+    let request = collection(&state.mongo_client)
+        .find_one_and_update(
+            doc! {
+                "token": &input.token,
+                "device_id": &device_id,
+                "status": "Pending",
+                "expires_at": { "$gt": &now },
+            },
+            doc! { "$set": { "status": "Sent", "sent_at": &now } },
+        )
+        .return_document(mongodb::options::ReturnDocument::After)
+        .await
+        .map_err(|_| Status::InternalServerError)?
+        .ok_or(Status::NotFound)?;
+
+    let cmd = request.to_server_command();
+    state
+        .cmd_tx
+        .send(cmd)
+        .map_err(|_| Status::InternalServerError)?;
+    info!("{:?} sent to {}", request.action, request.device_id);
+    Ok(Json(request))
+}
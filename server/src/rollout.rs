@@ -0,0 +1,159 @@
+use chrono::{DateTime, Utc};
+use log::info;
+use mongodb::bson::doc;
+use rocket::State;
+use rocket::get;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use serde::{Deserialize, Serialize};
+use tokio_stream::StreamExt;
+
+use crate::{AppState, BoardDetails, Schedule};
+
+// A sent schedule not confirmed within this time failed to apply
+const CONFIRM_TIMEOUT_SECONDS: i64 = 5 * 60;
+
+// Schedule delivery state of a board
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ScheduleRollout {
+    // Version the board should run
+    #[serde(default)]
+    pub target_version: Option<u32>,
+    #[serde(default)]
+    pub target_at: Option<String>,
+    // Last version sent to the board
+    #[serde(default)]
+    pub sent_version: Option<u32>,
+    #[serde(default)]
+    pub sent_at: Option<String>,
+    // Last version the board reported in its BoardInfo
+    #[serde(default)]
+    pub confirmed_version: Option<u32>,
+    #[serde(default)]
+    pub confirmed_at: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum RolloutStatus {
+    // Not sent yet, or sent and waiting for the board
+    Pending,
+    Applied,
+    // Sent but not confirmed in time
+    Failed,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct RolloutEntry {
+    pub device_id: String,
+    pub name: String,
+    pub status: RolloutStatus,
+    #[serde(flatten)]
+    pub rollout: ScheduleRollout,
+}
+
+fn collection(client: &mongodb::Client) -> mongodb::Collection<BoardDetails> {
+    client.database("sis").collection::<BoardDetails>("boards")
+}
+
+// New schedule version, every board should apply it
+pub async fn set_target(client: &mongodb::Client, version: u32) {
+    let _ = collection(client)
+        .update_many(
+            doc! {},
+            doc! { "$set": {
+                "rollout.target_version": version,
+                "rollout.target_at": Utc::now().to_rfc3339(),
+            } },
+        )
+        .await
+        .map_err(|e| info!("MongoDB update error: {:?}", e));
+}
+
+// Schedule version sent to the board
+pub async fn record_sent(client: &mongodb::Client, device_id: &str, version: u32) {
+    let _ = collection(client)
+        .update_one(
+            doc! { "device_id": device_id },
+            doc! { "$set": {
+                "rollout.sent_version": version,
+                "rollout.sent_at": Utc::now().to_rfc3339(),
+            } },
+        )
+        .await
+        .map_err(|e| info!("MongoDB update error: {:?}", e));
+}
+
+// Schedule version reported by the board, the time is kept while it does not change
+pub async fn record_confirmed(client: &mongodb::Client, device_id: &str, version: u32) {
+    let _ = collection(client)
+        .update_one(
+            doc! {
+                "device_id": device_id,
+                "rollout.confirmed_version": { "$ne": version },
+            },
+            doc! { "$set": {
+                "rollout.confirmed_version": version,
+                "rollout.confirmed_at": Utc::now().to_rfc3339(),
+            } },
+        )
+        .await
+        .map_err(|e| info!("MongoDB update error: {:?}", e));
+}
+
+fn status(rollout: &ScheduleRollout, target: u32, now: DateTime<Utc>) -> RolloutStatus {
+    if rollout.confirmed_version == Some(target) {
+        return RolloutStatus::Applied;
+    }
+    let sent_at = rollout
+        .sent_at
+        .as_deref()
+        .and_then(|s| DateTime::parse_from_rfc3339(s).ok());
+    match (rollout.sent_version, sent_at) {
+        (Some(sent), Some(sent_at))
+            if sent == target
+                && (now - sent_at.with_timezone(&Utc)).num_seconds() > CONFIRM_TIMEOUT_SECONDS =>
+        {
+            RolloutStatus::Failed
+        }
+        _ => RolloutStatus::Pending,
+    }
+}
+
+// List boards with the delivery state of the schedule
+// Boards without a target yet are compared with the current schedule version.
+#[get("/schedule/rollout")]
+pub async fn schedule_rollout(state: &State<AppState>) -> Result<Json<Vec<RolloutEntry>>, Status> {
+    let current = state
+        .mongo_client
+        .database("sis")
+        .collection::<Schedule>("schedule")
+        .find_one(doc! {})
+        .await
+        .map_err(|_| Status::InternalServerError)?
+        .map(|s| s.version);
+
+    let mut cursor = collection(&state.mongo_client)
+        .find(doc! {})
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let now = Utc::now();
+    let mut entries = Vec::new();
+    while let Some(board) = cursor.next().await {
+        let board = board.map_err(|_| Status::InternalServerError)?;
+        let mut rollout = board.rollout;
+        if rollout.target_version.is_none() {
+            rollout.target_version = current;
+        }
+        let status = match rollout.target_version {
+            Some(target) => status(&rollout, target, now),
+            None => RolloutStatus::Applied,
+        };
+        entries.push(RolloutEntry {
+            device_id: board.device_id,
+            name: board.name,
+            status,
+            rollout,
+        });
+    }
+    Ok(Json(entries))
+}